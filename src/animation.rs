@@ -0,0 +1,209 @@
+use crate::decode_queue::CancelToken;
+use crate::format::FileFormat;
+use crate::image_loader::ImageRef;
+use crate::load_error::LoadError;
+use anyhow::*;
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+/// Frames with a shorter delay are shown for `DEFAULT_DELAY`, as browsers do.
+const MIN_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// Playback speeds, as multiples of the delays stored in the file.
+const SPEEDS: [f64; 7] = [0.125, 0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+const NORMAL_SPEED: usize = 3;
+
+/// Decode all frames of an animated GIF, APNG or WebP, composed to full size.
+/// Returns `None` for other formats and for PNG and WebP files that are not animated;
+/// a still GIF is returned as a single frame.
+/// All frames are kept at full size, so decoding stops with an error once they
+/// take more than `max_bytes`, before a long animation exhausts memory.
+pub fn decode_frames(
+    bytes: &[u8],
+    format: FileFormat,
+    max_bytes: usize,
+    cancel: &CancelToken,
+) -> Result<Option<Vec<(DynamicImage, Duration)>>> {
+    let cursor = Cursor::new(bytes);
+    let frames = match format {
+        FileFormat::Gif => GifDecoder::new(cursor)?.into_frames(),
+        FileFormat::Png => {
+            let decoder = PngDecoder::new(cursor)?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        FileFormat::WebP => {
+            let decoder = WebPDecoder::new(cursor)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+    let mut decoded = Vec::new();
+    let mut decoded_bytes = 0;
+    for frame in frames {
+        cancel.check()?;
+        let frame = frame?;
+        decoded_bytes += frame.buffer().len();
+        if decoded_bytes > max_bytes {
+            let reason = format!("animation exceeds the RAM budget at frame {}", decoded.len() + 1);
+            return Err(LoadError::Unsupported(reason).into());
+        }
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay = Duration::from_secs_f64(numer as f64 / denom.max(1) as f64 / 1000.0);
+        let delay = if delay < MIN_DELAY {
+            DEFAULT_DELAY
+        } else {
+            delay
+        };
+        decoded.push((DynamicImage::ImageRgba8(frame.into_buffer()), delay));
+    }
+    if decoded.is_empty() {
+        return Err(anyhow!("animation without frames"));
+    }
+    Ok(Some(decoded))
+}
+
+/// Which frame of the current image's animation is shown, and when the next one is due.
+pub struct Playback {
+    image_ref: Option<ImageRef>,
+    frame: usize,
+    next_frame_at: Option<Instant>,
+    paused: bool,
+    speed: usize,
+}
+
+impl Playback {
+    pub fn new() -> Self {
+        Self {
+            image_ref: None,
+            frame: 0,
+            next_frame_at: None,
+            paused: false,
+            speed: NORMAL_SPEED,
+        }
+    }
+
+    fn scaled(&self, delay: Duration) -> Duration {
+        delay.div_f64(SPEEDS[self.speed])
+    }
+
+    /// Advance to the frame that is due at `now` for `iref` with per-frame `delays`.
+    /// Returns whether the shown frame changed.
+    pub fn update(&mut self, iref: &ImageRef, delays: &[Duration], now: Instant) -> bool {
+        if self.image_ref.as_ref() != Some(iref) {
+            self.image_ref = Some(iref.clone());
+            self.frame = 0;
+            self.next_frame_at = None;
+        }
+        if self.paused || delays.len() < 2 {
+            self.next_frame_at = None;
+            return false;
+        }
+        // frames of a higher resolution layer replace the thumbnail's single frame
+        self.frame %= delays.len();
+        let mut next = match self.next_frame_at {
+            Some(next) => next,
+            None => now + self.scaled(delays[self.frame]),
+        };
+        let mut changed = false;
+        while now >= next {
+            self.frame = (self.frame + 1) % delays.len();
+            next += self.scaled(delays[self.frame]);
+            changed = true;
+        }
+        self.next_frame_at = Some(next);
+        changed
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// When the next frame is due, `None` for still images and while paused.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.next_frame_at
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    /// Show the next or previous frame and pause.
+    pub fn step(&mut self, forward: bool, frame_count: usize) {
+        self.paused = true;
+        if frame_count == 0 {
+            return;
+        }
+        self.frame = if forward {
+            (self.frame + 1) % frame_count
+        } else {
+            (self.frame + frame_count - 1) % frame_count
+        };
+    }
+
+    /// Double or halve the playback speed, within limits. Returns the new speed factor.
+    pub fn change_speed(&mut self, faster: bool) -> f64 {
+        self.speed = if faster {
+            (self.speed + 1).min(SPEEDS.len() - 1)
+        } else {
+            self.speed.saturating_sub(1)
+        };
+        // the current frame keeps its remaining time at the old speed
+        SPEEDS[self.speed]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_ref(name: &str) -> ImageRef {
+        ImageRef {
+            path: name.into(),
+            page: 0,
+            entry: None,
+        }
+    }
+
+    #[test]
+    fn still_images_have_no_deadline() {
+        let mut playback = Playback::new();
+        assert!(!playback.update(&image_ref("a.png"), &[Duration::ZERO], Instant::now()));
+        assert_eq!(playback.deadline(), None);
+    }
+
+    #[test]
+    fn frames_advance_by_their_delays() {
+        let iref = image_ref("a.gif");
+        let delays = [Duration::from_millis(100), Duration::from_millis(50)];
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut playback = Playback::new();
+        assert!(!playback.update(&iref, &delays, start));
+        assert_eq!(playback.deadline(), Some(at(100)));
+        assert!(!playback.update(&iref, &delays, at(99)));
+        assert!(playback.update(&iref, &delays, at(100)));
+        assert_eq!(playback.frame(), 1);
+        // a late update skips the frames that were due meanwhile
+        assert!(playback.update(&iref, &delays, at(260)));
+        assert_eq!(playback.frame(), 1);
+        assert_eq!(playback.deadline(), Some(at(300)));
+        // another image starts from its first frame
+        assert!(!playback.update(&image_ref("b.gif"), &delays, at(260)));
+        assert_eq!(playback.frame(), 0);
+    }
+}
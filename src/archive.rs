@@ -0,0 +1,273 @@
+use crate::raw;
+use crate::sort::natural_cmp;
+use anyhow::*;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// File extensions of the supported archives, lower case. CBZ and CBT are comic book archives.
+const ZIP_EXTENSIONS: [&str; 2] = ["zip", "cbz"];
+const TAR_EXTENSIONS: [&str; 2] = ["tar", "cbt"];
+
+/// Entries are listed by name, without reading their contents.
+const IMAGE_EXTENSIONS: [&str; 15] = [
+    "jpg", "jpeg", "jpe", "mpo", "png", "apng", "webp", "gif", "tif", "tiff", "bmp", "exr", "hdr",
+    "svg", "svgz",
+];
+
+/// Deflate compresses at most about 1032:1, so larger sizes in a header are damaged.
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+/// An image inside a ZIP or TAR archive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ArchiveEntry {
+    pub archive: PathBuf,
+    /// Name of the entry as stored in the archive
+    pub name: String,
+    location: Location,
+}
+
+/// Where the entry is stored, recorded while listing so that reading it seeks
+/// straight there instead of scanning the archive again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Location {
+    /// Position of the local header, and the uncompressed size
+    Zip { header: u64, size: u64 },
+    /// Position and size of the data
+    Tar { data: u64, size: u64 },
+}
+
+enum Kind {
+    Zip,
+    Tar,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    if ZIP_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Kind::Zip)
+    } else if TAR_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Kind::Tar)
+    } else {
+        None
+    }
+}
+
+pub fn is_archive_path(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+/// Whether an entry looks like an image; hidden files and macOS resource forks are skipped.
+fn is_image_entry(name: &str) -> bool {
+    let path = Path::new(name);
+    // `./a.jpg`, as `tar -C dir .` stores entries, is not hidden
+    let hidden = path.components().any(|c| match c {
+        Component::Normal(c) => {
+            let c = c.to_string_lossy();
+            c.starts_with('.') || c == "__MACOSX"
+        }
+        _ => false,
+    });
+    let is_image = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .is_some_and(|ext| {
+            IMAGE_EXTENSIONS.contains(&ext.as_str()) || raw::RAW_EXTENSIONS.contains(&ext.as_str())
+        });
+    is_image && !hidden
+}
+
+/// The image entries of an archive, sorted naturally by name.
+pub fn list_images(archive: &Path) -> Result<Vec<ArchiveEntry>> {
+    let entries = match kind(archive) {
+        Some(Kind::Zip) => list_zip(archive),
+        Some(Kind::Tar) => list_tar(archive),
+        None => Err(anyhow!("not an archive")),
+    }
+    .map_err(|e| anyhow!("{}: {}", archive.display(), e))?;
+    let mut entries: Vec<(String, Location)> =
+        entries.into_iter().filter(|(n, _)| is_image_entry(n)).collect();
+    entries.sort_by(|(a, _), (b, _)| natural_cmp(Path::new(a), Path::new(b)));
+    Ok(entries
+        .into_iter()
+        .map(|(name, location)| ArchiveEntry {
+            archive: archive.to_path_buf(),
+            name,
+            location,
+        })
+        .collect())
+}
+
+fn list_zip(archive: &Path) -> Result<Vec<(String, Location)>> {
+    let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))?;
+    let mut entries = Vec::new();
+    for index in 0..zip.len() {
+        // only images are looked up, which reads their local header
+        let name = zip.name_for_index(index).unwrap_or_default();
+        if name.ends_with('/') || !is_image_entry(name) {
+            continue;
+        }
+        let file = zip.by_index_raw(index)?;
+        let location = Location::Zip {
+            header: file.header_start(),
+            size: file.size(),
+        };
+        entries.push((file.name().to_string(), location));
+    }
+    Ok(entries)
+}
+
+/// The regular files in a TAR archive, with the position and size of their data.
+fn list_tar(archive: &Path) -> Result<Vec<(String, Location)>> {
+    let mut tar = tar::Archive::new(BufReader::new(File::open(archive)?));
+    let mut files = Vec::new();
+    // seeking skips over the data of each entry instead of reading it
+    for entry in tar.entries_with_seek()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let location = Location::Tar {
+                data: entry.raw_file_position(),
+                size: entry.size(),
+            };
+            files.push((name, location));
+        }
+    }
+    Ok(files)
+}
+
+/// Read the contents of an entry into memory, without extracting it to disk.
+pub fn read_entry(entry: &ArchiveEntry) -> Result<Vec<u8>> {
+    let archive = &entry.archive;
+    let result = match entry.location {
+        Location::Zip { header, size } => read_zip_entry(entry, header, size),
+        Location::Tar { data, size } => read_tar_entry(archive, data, size),
+    };
+    result.map_err(|e| anyhow!("{}: {}: {}", archive.display(), entry.name, e))
+}
+
+/// Read the entry from its local header. Entries whose sizes only follow their
+/// data, as streaming writers store them, are read through the central directory.
+fn read_zip_entry(entry: &ArchiveEntry, header: u64, size: u64) -> Result<Vec<u8>> {
+    let file = File::open(&entry.archive)?;
+    // sizes are checked before allocating, so that a damaged header fails to load
+    // instead of aborting on a huge allocation
+    let max_size = file.metadata()?.len().saturating_mul(MAX_DEFLATE_RATIO);
+    if size > max_size {
+        return Err(anyhow!("entry claims {} bytes, more than the archive can hold", size));
+    }
+    let mut reader = BufReader::new(file);
+    reader.seek(SeekFrom::Start(header))?;
+    let mut bytes = Vec::with_capacity(size as usize);
+    if let Result::Ok(Some(mut file)) = zip::read::read_zipfile_from_stream(&mut reader) {
+        if file.name() == entry.name
+            && file.read_to_end(&mut bytes).is_ok()
+            && bytes.len() as u64 == size
+        {
+            return Ok(bytes);
+        }
+    }
+    reader.rewind()?;
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let file = zip.by_name(&entry.name)?;
+    if file.size() > max_size {
+        return Err(anyhow!("entry claims {} bytes, more than the archive can hold", file.size()));
+    }
+    bytes.clear();
+    file.take(max_size).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_tar_entry(archive: &Path, data: u64, size: u64) -> Result<Vec<u8>> {
+    let mut file = File::open(archive)?;
+    // TAR stores data uncompressed, a size beyond the end of the file is damaged
+    if data.saturating_add(size) > file.metadata()?.len() {
+        return Err(anyhow!("entry cut short"));
+    }
+    file.seek(SeekFrom::Start(data))?;
+    let mut bytes = Vec::with_capacity(size as usize);
+    file.take(size).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != size {
+        return Err(anyhow!("entry cut short"));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn image_entries() {
+        assert!(is_image_entry("a.jpg"));
+        assert!(is_image_entry("./a.jpg"));
+        assert!(is_image_entry("dir/B.JPEG"));
+        assert!(is_image_entry("dir/raw.cr2"));
+        assert!(!is_image_entry("notes.txt"));
+        assert!(!is_image_entry(".a.jpg"));
+        assert!(!is_image_entry("./.thumbnails/a.jpg"));
+        assert!(!is_image_entry("__MACOSX/dir/._a.jpg"));
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vrr-test-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn read_zip_entries() {
+        let path = temp_path("entries.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, contents) in [("b.png", &b"second"[..]), ("a.jpg", b"first"), ("c.txt", b"")] {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let entries = list_images(&path).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a.jpg", "b.png"]);
+        assert_eq!(read_entry(&entries[0]).unwrap(), b"first");
+        assert_eq!(read_entry(&entries[1]).unwrap(), b"second");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn read_tar_entries() {
+        let path = temp_path("entries.tar");
+        let mut tar = tar::Builder::new(File::create(&path).unwrap());
+        for (name, contents) in [("dir/b.png", &b"second"[..]), ("a.jpg", b"first")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            tar.append_data(&mut header, name, contents).unwrap();
+        }
+        tar.into_inner().unwrap();
+
+        let entries = list_images(&path).unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["a.jpg", "dir/b.png"]);
+        assert_eq!(read_entry(&entries[0]).unwrap(), b"first");
+        assert_eq!(read_entry(&entries[1]).unwrap(), b"second");
+        std::fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn damaged_sizes_fail_to_load() {
+        let path = temp_path("damaged.tar");
+        std::fs::write(&path, [0; 1024]).unwrap();
+        for location in [
+            Location::Zip { header: 0, size: 1 << 40 },
+            Location::Tar { data: 512, size: 1 << 40 },
+        ] {
+            let entry = ArchiveEntry {
+                archive: path.clone(),
+                name: "a.jpg".to_string(),
+                location,
+            };
+            assert!(read_entry(&entry).is_err());
+        }
+        std::fs::remove_file(path).ok();
+    }
+}
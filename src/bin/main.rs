@@ -4,7 +4,8 @@ use vrr::config::Config;
 
 fn main() -> anyhow::Result<()> {
     vrr::logger::init();
-    let config = Config::parse();
+    let mut config = Config::parse();
+    config.read_path_lists()?;
     pollster::block_on(run(config));
     Ok(())
 }
@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Share of the RAM budget kept in released buffers for reuse, see `reserve`.
+const RAM_BUDGET_SHARE: usize = 8;
+
+/// Bytes kept in released buffers for reuse; beyond this the smallest are freed.
+static MAX_POOLED_BYTES: AtomicUsize = AtomicUsize::new(128 << 20);
+
+/// Smaller buffers are cheap to allocate and left to the allocator.
+const MIN_POOLED_SIZE: usize = 1 << 20;
+
+/// Released pixel buffers, reused for the next decodes of similar size, so that
+/// scrolling through large images does not map and fault in fresh memory each time.
+fn pool() -> &'static Mutex<Vec<Vec<u8>>> {
+    static POOL: OnceLock<Mutex<Vec<Vec<u8>>>> = OnceLock::new();
+    POOL.get_or_init(|| Mutex::new(Vec::new()))
+}
+
+/// Set aside part of `ram_budget` for pooled buffers; returns what is left for
+/// decoded images.
+pub fn reserve(ram_budget: usize) -> usize {
+    let pooled = ram_budget / RAM_BUDGET_SHARE;
+    MAX_POOLED_BYTES.store(pooled, Ordering::Relaxed);
+    ram_budget - pooled
+}
+
+/// A zeroed buffer of `len` bytes, reusing the smallest pooled buffer that fits
+/// and is at most an eighth larger; the excess counts towards the RAM budget.
+pub fn take(len: usize) -> Vec<u8> {
+    if len >= MIN_POOLED_SIZE {
+        let mut pool = pool().lock().unwrap();
+        let fitting = pool
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| (len..=len + len / 8).contains(&b.capacity()))
+            .min_by_key(|(_, b)| b.capacity())
+            .map(|(index, _)| index);
+        if let Some(index) = fitting {
+            let mut buffer = pool.swap_remove(index);
+            drop(pool);
+            buffer.clear();
+            buffer.resize(len, 0);
+            return buffer;
+        }
+    }
+    vec![0; len]
+}
+
+/// Return a buffer that is no longer needed to the pool.
+pub fn recycle(buffer: Vec<u8>) {
+    let size = buffer.capacity();
+    let max_pooled = MAX_POOLED_BYTES.load(Ordering::Relaxed);
+    if !(MIN_POOLED_SIZE..=max_pooled).contains(&size) {
+        return;
+    }
+    let mut pool = pool().lock().unwrap();
+    while pool.iter().map(Vec::capacity).sum::<usize>() + size > max_pooled {
+        let (index, smallest) = pool
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, b)| b.capacity())
+            .map(|(index, b)| (index, b.capacity()))
+            .unwrap();
+        if smallest >= size {
+            return;
+        }
+        pool.swap_remove(index);
+    }
+    pool.push(buffer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_buffers_of_similar_size() {
+        let len = 2 * MIN_POOLED_SIZE;
+        let mut buffer = take(len);
+        buffer[0] = 1;
+        let pointer = buffer.as_ptr();
+        recycle(buffer);
+        // too small for the request
+        let larger = take(2 * len);
+        assert_ne!(larger.as_ptr(), pointer);
+        // fits, and comes back zeroed
+        let reused = take(len - len / 16);
+        assert_eq!(reused.as_ptr(), pointer);
+        assert_eq!(reused.len(), len - len / 16);
+        assert!(reused.iter().all(|&b| b == 0));
+        // far too large for a small request
+        recycle(reused);
+        let smaller = take(MIN_POOLED_SIZE);
+        assert_ne!(smaller.as_ptr(), pointer);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::format::{read_jpeg_segments, FileFormat};
+use anyhow::*;
+use image::{DynamicImage, ImageBuffer, Rgba};
+use log::{debug, error, warn};
+use qcms::{DataType, Intent, Profile, Transform};
+use rayon::prelude::*;
+use rexiv2::Metadata;
+use std::io::Read;
+use std::path::Path;
+
+/// Identifier at the start of JPEG APP2 segments that carry an ICC profile.
+const JPEG_ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+
+/// Read the ICC profile embedded in a JPEG (APP2) or PNG (iCCP) image, from the
+/// contents of the file or archive entry named `path`.
+pub fn read_icc_profile_from(
+    path: &Path,
+    reader: impl Read,
+    format: FileFormat,
+) -> Option<Vec<u8>> {
+    let result = match format {
+        FileFormat::Jpeg => read_jpeg_icc_profile(reader),
+        FileFormat::Png => read_png_icc_profile(reader),
+        _ => return None,
+    };
+    result
+        .map_err(|e| debug!("{}: failed to read ICC profile: {}", path.display(), e))
+        .ok()?
+}
+
+/// Collect the APP2 chunks of a profile, which may be split across several segments.
+/// Stops at the start of the image data, so only the header is read.
+fn read_jpeg_icc_profile(reader: impl Read) -> Result<Option<Vec<u8>>> {
+    let mut chunks: Vec<(u8, Vec<u8>)> = Vec::new();
+    let header_len = JPEG_ICC_MARKER.len() + 2;
+    read_jpeg_segments(reader, |marker, _, payload| {
+        if marker == 0xE2 && payload.len() >= header_len && payload.starts_with(JPEG_ICC_MARKER) {
+            // sequence number (1-based) and chunk count follow the identifier
+            let seq = payload[JPEG_ICC_MARKER.len()];
+            chunks.push((seq, payload[header_len..].to_vec()));
+        }
+    })?;
+    if chunks.is_empty() {
+        return Ok(None);
+    }
+    chunks.sort_by_key(|(seq, _)| *seq);
+    Ok(Some(
+        chunks.into_iter().flat_map(|(_, chunk)| chunk).collect(),
+    ))
+}
+
+/// The png decoder stops reading at the first IDAT chunk, after the iCCP chunk.
+fn read_png_icc_profile(reader: impl Read) -> Result<Option<Vec<u8>>> {
+    let reader = png::Decoder::new(reader).read_info()?;
+    Ok(reader.info().icc_profile.as_ref().map(|p| p.to_vec()))
+}
+
+/// Adobe RGB (1998), for files that declare it in EXIF instead of embedding a profile.
+fn adobe_rgb_profile() -> Option<Box<Profile>> {
+    let xy = |x, y| qcms::CIE_xyY { x, y, Y: 1.0 };
+    let gamma = 563.0 / 256.0;
+    Profile::new_rgb_with_gamma_set(
+        xy(0.3127, 0.3290),
+        qcms::CIE_xyYTRIPLE {
+            red: xy(0.64, 0.33),
+            green: xy(0.21, 0.71),
+            blue: xy(0.15, 0.06),
+        },
+        gamma,
+        gamma,
+        gamma,
+    )
+}
+
+/// Colour space declared by the EXIF tags, following the DCF convention:
+/// "Uncalibrated" with interoperability index R03 means Adobe RGB.
+fn exif_profile(path: &Path, metadata: &Metadata) -> Option<Box<Profile>> {
+    match metadata.get_tag_string("Exif.Photo.ColorSpace") {
+        Result::Ok(s) if s == "1" => None,
+        Result::Ok(s) if s == "65535" => {
+            match metadata.get_tag_string("Exif.Iop.InteroperabilityIndex") {
+                Result::Ok(index) if index == "R03" => {
+                    debug!("{}: Adobe RGB by EXIF", path.display());
+                    adobe_rgb_profile()
+                }
+                _ => {
+                    debug!(
+                        "{}: uncalibrated color space, assuming sRGB",
+                        path.display()
+                    );
+                    None
+                }
+            }
+        }
+        Result::Ok(s) => {
+            error!("{}: Unknown color space: {}", path.display(), s);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// Converts decoded images from their own colour space to the display's.
+/// Without a display profile, the display is assumed to be sRGB.
+pub struct ColorManagement {
+    srgb: Box<Profile>,
+    display: Option<Box<Profile>>,
+}
+
+impl ColorManagement {
+    pub fn new(display_profile: Option<&Path>) -> Result<Self> {
+        let mut srgb = Profile::new_sRGB();
+        srgb.precache_output_transform();
+        let display = match display_profile {
+            Some(path) => {
+                let bytes =
+                    std::fs::read(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+                let mut profile = Profile::new_from_slice(&bytes, false)
+                    .ok_or(anyhow!("{}: invalid ICC profile", path.display()))?;
+                profile.precache_output_transform();
+                Some(profile)
+            }
+            None => None,
+        };
+        Ok(Self { srgb, display })
+    }
+
+    /// Profile of the image at `path`: the embedded `icc` profile, declared in EXIF,
+    /// or `None` for sRGB.
+    pub fn source_profile(
+        &self,
+        path: &Path,
+        icc: Option<Vec<u8>>,
+        metadata: Option<&Metadata>,
+    ) -> Option<Box<Profile>> {
+        if let Some(bytes) = icc {
+            match Profile::new_from_slice(&bytes, false) {
+                Some(profile) if profile.is_sRGB() => return None,
+                Some(profile) => {
+                    debug!("{}: embedded ICC profile", path.display());
+                    return Some(profile);
+                }
+                None => warn!("{}: invalid embedded ICC profile", path.display()),
+            }
+        }
+        metadata
+            .filter(|m| m.has_exif())
+            .and_then(|m| exif_profile(path, m))
+    }
+
+    /// Whether images in `source` are shown unconverted, i.e. both are sRGB.
+    pub fn is_passthrough(&self, source: Option<&Profile>) -> bool {
+        source.is_none() && self.display.is_none()
+    }
+
+    /// Convert from `source` (sRGB if `None`) to the display profile.
+    pub fn to_display(&self, image: DynamicImage, source: Option<&Profile>) -> DynamicImage {
+        self.convert(image, source, self.display.as_deref())
+    }
+
+    /// Convert from `source` to sRGB, e.g. for the shared thumbnail cache.
+    pub fn to_srgb(&self, image: DynamicImage, source: Option<&Profile>) -> DynamicImage {
+        self.convert(image, source, None)
+    }
+
+    fn convert(
+        &self,
+        image: DynamicImage,
+        source: Option<&Profile>,
+        target: Option<&Profile>,
+    ) -> DynamicImage {
+        if source.is_none() && target.is_none() {
+            return image;
+        }
+        let input = source.unwrap_or(&self.srgb);
+        let output = target.unwrap_or(&self.srgb);
+        let Some(transform) = Transform::new(input, output, DataType::RGBA8, Intent::default())
+        else {
+            warn!("unsupported ICC profile, showing colours unconverted");
+            return image;
+        };
+        match image {
+            DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_) => {
+                let mut rgba = image.into_rgba8();
+                transform.apply(&mut rgba);
+                DynamicImage::ImageRgba8(rgba)
+            }
+            DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => {
+                let mut rgba = image.into_rgba16();
+                transform_16bit(&transform, &mut rgba);
+                DynamicImage::ImageRgba16(rgba)
+            }
+            image => {
+                warn!(
+                    "colour management not supported for {:?}, showing colours unconverted",
+                    image.color()
+                );
+                image
+            }
+        }
+    }
+}
+
+/// Spacing of the lookup table for 16-bit images, in 8-bit code values.
+const LUT_STEP: usize = 5;
+const LUT_SIZE: usize = 255 / LUT_STEP + 1;
+
+/// qcms only transforms 8-bit pixels. 16-bit pixels are converted through a
+/// lookup table of transformed 8-bit colours, interpolated trilinearly.
+fn transform_16bit(transform: &Transform, image: &mut ImageBuffer<Rgba<u16>, Vec<u16>>) {
+    let mut lut: Vec<u8> = (0..LUT_SIZE.pow(3))
+        .flat_map(|i| {
+            let node = |n: usize| (n * LUT_STEP) as u8;
+            let (r, g, b) = (i / (LUT_SIZE * LUT_SIZE), i / LUT_SIZE % LUT_SIZE, i % LUT_SIZE);
+            [node(r), node(g), node(b), 255]
+        })
+        .collect();
+    transform.apply(&mut lut);
+    let scale = (LUT_SIZE - 1) as f32 / u16::MAX as f32;
+    image.par_chunks_mut(4).for_each(|pixel| {
+        // the lower node and the distance to it along each axis
+        let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|v| {
+            let x = v as f32 * scale;
+            let node = (x as usize).min(LUT_SIZE - 2);
+            (node, x - node as f32)
+        });
+        let mut out = [0.0f32; 3];
+        for corner in 0..8 {
+            let pick = |(node, t): (usize, f32), bit: usize| {
+                if corner & bit != 0 {
+                    (node + 1, t)
+                } else {
+                    (node, 1.0 - t)
+                }
+            };
+            let (ri, rw) = pick(r, 4);
+            let (gi, gw) = pick(g, 2);
+            let (bi, bw) = pick(b, 1);
+            let weight = rw * gw * bw;
+            let entry = ((ri * LUT_SIZE + gi) * LUT_SIZE + bi) * 4;
+            for (o, &v) in out.iter_mut().zip(&lut[entry..entry + 3]) {
+                *o += weight * v as f32;
+            }
+        }
+        for (c, o) in pixel.iter_mut().zip(out) {
+            *c = (o * 257.0).round() as u16;
+        }
+    });
+}
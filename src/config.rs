@@ -1,10 +1,167 @@
 use std::path::PathBuf;
 use clap::Parser;
+use crate::scan::read_path_list;
+use crate::sort::SortOrder;
+use crate::texture::{FilterMode, ToneMapping};
 #[derive(Parser, Clone)]
 pub struct Config {
-    #[clap(default_value = "")]
-    pub path: PathBuf,
+    /// Images and directories to show, `-` reads a list of paths from stdin
+    pub paths: Vec<PathBuf>,
 
+    /// Paths read from stdin or a file list are separated by NUL instead of newline
+    #[clap(short = '0', long)]
+    pub null: bool,
+
+    /// Read a list of paths from a file (repeatable)
+    #[clap(long)]
+    pub filelist: Vec<PathBuf>,
+
+    /// Set by `read_path_lists` when a single path was given, whose
+    /// directory is then browsed
+    #[clap(skip)]
+    pub browse_parent: bool,
+
+    /// Maximum number of neighbours on each side preloaded at full size,
+    /// if they fit into the memory budgets
     #[clap(long, default_value_t = 4)]
     pub preload: usize,
+
+    /// GPU memory for textures, e.g. `512M` or `2G`. Further away images are
+    /// downgraded to thumbnails, then dropped to stay within it
+    #[clap(long, value_parser = parse_byte_size, default_value = "1G")]
+    pub gpu_budget: usize,
+
+    /// Memory for decoded images, kept so that they need not be decoded again
+    #[clap(long, value_parser = parse_byte_size, default_value = "1G")]
+    pub ram_budget: usize,
+
+    /// Bytes of decoded pixels copied to the GPU per frame; larger images
+    /// are uploaded over several frames so that input stays responsive
+    #[clap(long, value_parser = parse_byte_size, default_value = "16M")]
+    pub upload_budget: usize,
+
+    /// Order in which images are shown
+    #[clap(long, value_enum, default_value_t = SortOrder::Natural)]
+    pub sort: SortOrder,
+
+    /// Reverse the sort order
+    #[clap(long)]
+    pub reverse: bool,
+
+    /// Seed for `--sort random`, picked at startup if not given
+    #[clap(long)]
+    pub seed: Option<u64>,
+
+    /// Descend into subdirectories
+    #[clap(short, long)]
+    pub recursive: bool,
+
+    /// Maximum directory depth to descend into (implies --recursive)
+    #[clap(long)]
+    pub max_depth: Option<usize>,
+
+    /// Only show files whose path relative to the directory matches this glob (repeatable)
+    #[clap(long)]
+    pub include: Vec<String>,
+
+    /// Skip files and directories whose relative path matches this glob (repeatable)
+    #[clap(long)]
+    pub exclude: Vec<String>,
+
+    /// Do not watch directories for added, removed and renamed files
+    #[clap(long)]
+    pub no_watch: bool,
+
+    /// Jump to new images as they appear, e.g. for tethered shooting
+    #[clap(long)]
+    pub follow: bool,
+
+    /// How images are resampled when zoomed, can be cycled with `i`
+    #[clap(long, value_enum, default_value_t = FilterMode::Bicubic)]
+    pub filter: FilterMode,
+
+    /// Exposure adjustment in stops, changed with `e` and `E`
+    #[clap(long, default_value_t = 0.0, allow_hyphen_values = true)]
+    pub exposure: f32,
+
+    /// Tone mapping for HDR images, can be cycled with `t`
+    #[clap(long, value_enum, default_value_t = ToneMapping::None)]
+    pub tonemap: ToneMapping,
+
+    /// Do not read or write the shared thumbnail cache in $XDG_CACHE_HOME/thumbnails
+    #[clap(long)]
+    pub no_thumbnail_cache: bool,
+
+    /// ICC profile of the display; images are converted to sRGB if not given
+    #[clap(long)]
+    pub display_profile: Option<PathBuf>,
+}
+
+/// Parse sizes like `1500000`, `512K`, `256M` or `1.5G` (binary units).
+pub fn parse_byte_size(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len()));
+    let unit = unit.to_ascii_uppercase();
+    let factor: u64 = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("unknown unit: {}", unit)),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid size {}: {}", s, e))?;
+    if number < 0.0 {
+        return Err(format!("negative size: {}", s));
+    }
+    Ok((number * factor as f64) as usize)
+}
+
+impl Config {
+    /// Replace `-` in `paths` by the paths read from stdin and append the
+    /// contents of all file lists, so that they are only read once.
+    /// Without any paths, the current directory is shown.
+    pub fn read_path_lists(&mut self) -> anyhow::Result<()> {
+        if self.paths.is_empty() && self.filelist.is_empty() {
+            self.paths.push(PathBuf::from("."));
+        }
+        self.browse_parent = self.filelist.is_empty()
+            && self.paths.len() == 1
+            && self.paths[0].as_os_str() != "-";
+        let mut paths = Vec::new();
+        for path in std::mem::take(&mut self.paths) {
+            if path.as_os_str() == "-" {
+                paths.extend(read_path_list(std::io::stdin().lock(), self.null)?);
+            } else {
+                paths.push(path);
+            }
+        }
+        for filelist in std::mem::take(&mut self.filelist) {
+            let file = std::fs::File::open(&filelist)
+                .map_err(|e| anyhow::anyhow!("{}: {}", filelist.display(), e))?;
+            paths.extend(read_path_list(file, self.null)?);
+        }
+        self.paths = paths;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_sizes_with_binary_units() {
+        assert_eq!(parse_byte_size("1500000"), Ok(1500000));
+        assert_eq!(parse_byte_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_byte_size("256mb"), Ok(256 << 20));
+        assert_eq!(parse_byte_size("2 GiB"), Ok(2 << 30));
+        assert_eq!(parse_byte_size("1.5G"), Ok(3 << 29));
+        assert!(parse_byte_size("12X").is_err());
+        assert!(parse_byte_size("-1G").is_err());
+        assert!(parse_byte_size("G").is_err());
+    }
 }
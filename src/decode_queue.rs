@@ -0,0 +1,163 @@
+use crate::image_loader::ImageRequest;
+use crate::load_error::LoadError;
+use crate::texture::ImageResolution;
+use anyhow::*;
+use log::{debug, error};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// Set when a queued or running request is no longer wanted.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Fails if cancelled, so that work can stop early with `?`.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(LoadError::Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+/// Distance from the current image, then resolution. Lower runs first.
+pub type Priority = (usize, ImageResolution);
+
+struct Job {
+    priority: Priority,
+    /// Insertion order, equal priorities run first come, first served
+    seq: u64,
+    request: ImageRequest,
+    cancel: CancelToken,
+    run: Box<dyn FnOnce(&CancelToken) + Send>,
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Job {}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Job {
+    // BinaryHeap pops the greatest element, so the order is reversed
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.priority, other.seq).cmp(&(self.priority, self.seq))
+    }
+}
+
+#[derive(Default)]
+struct State {
+    jobs: BinaryHeap<Job>,
+    seq: u64,
+    shutdown: bool,
+}
+
+/// Runs decode jobs on a fixed set of worker threads, highest priority first.
+pub struct DecodeQueue {
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl DecodeQueue {
+    pub fn new(num_threads: usize) -> Self {
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        for i in 0..num_threads {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name(format!("decode-{}", i))
+                .spawn(move || Self::work(&shared))
+                .expect("failed to spawn decode thread");
+        }
+        Self { shared }
+    }
+
+    fn work(shared: &(Mutex<State>, Condvar)) {
+        let (state, condvar) = shared;
+        loop {
+            let job = {
+                let mut state = state.lock().unwrap();
+                loop {
+                    if state.shutdown {
+                        return;
+                    }
+                    if let Some(job) = state.jobs.pop() {
+                        break job;
+                    }
+                    state = condvar.wait(state).unwrap();
+                }
+            };
+            if job.cancel.is_cancelled() {
+                debug!("cancelled before start: {:?}", job.request);
+                continue;
+            }
+            let (request, cancel, run) = (job.request, job.cancel, job.run);
+            if catch_unwind(AssertUnwindSafe(|| run(&cancel))).is_err() {
+                error!("decode panicked: {:?}", request);
+            }
+        }
+    }
+
+    pub fn push(
+        &self,
+        request: ImageRequest,
+        priority: Priority,
+        cancel: CancelToken,
+        run: impl FnOnce(&CancelToken) + Send + 'static,
+    ) {
+        let (state, condvar) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        state.seq += 1;
+        let seq = state.seq;
+        state.jobs.push(Job {
+            priority,
+            seq,
+            request,
+            cancel,
+            run: Box::new(run),
+        });
+        condvar.notify_one();
+    }
+
+    /// Update the priorities of queued jobs, e.g. after the current image changed.
+    /// Cancelled jobs are dropped.
+    pub fn reprioritize(&self, priority: impl Fn(&ImageRequest) -> Priority) {
+        let mut state = self.shared.0.lock().unwrap();
+        let jobs = std::mem::take(&mut state.jobs);
+        state.jobs = jobs
+            .into_iter()
+            .filter(|job| !job.cancel.is_cancelled())
+            .map(|job| Job {
+                priority: priority(&job.request),
+                ..job
+            })
+            .collect();
+    }
+}
+
+impl Drop for DecodeQueue {
+    /// Workers finish their current job and exit.
+    fn drop(&mut self) {
+        let (state, condvar) = &*self.shared;
+        state.lock().unwrap().shutdown = true;
+        condvar.notify_all();
+    }
+}
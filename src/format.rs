@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Number of leading bytes needed to tell the supported formats apart.
+const MAGIC_LEN: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Gif,
+    Tiff,
+    Bmp,
+    Exr,
+    Hdr,
+    /// Camera RAW, shown through its embedded preview
+    Raw,
+    /// Vector graphics, rasterised for the current zoom
+    Svg,
+}
+
+impl FileFormat {
+    /// Detect the format from the leading bytes of a file.
+    pub fn from_magic(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0xFF, 0xD8, 0xFF, ..] => Some(Self::Jpeg),
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(Self::Png),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(Self::WebP),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(Self::Gif),
+            // Canon CR2 is TIFF with a signature after the header
+            [b'I', b'I', 0x2A, 0x00, _, _, _, _, b'C', b'R', ..] => Some(Self::Raw),
+            [_, _, _, _, b'f', b't', b'y', b'p', b'c', b'r', b'x', b' ', ..] => Some(Self::Raw),
+            [b'F', b'U', b'J', b'I', b'F', b'I', b'L', b'M', ..] => Some(Self::Raw),
+            [b'I', b'I', 0x2A, 0x00, ..] | [b'M', b'M', 0x00, 0x2A, ..] => Some(Self::Tiff),
+            [b'B', b'M', ..] => Some(Self::Bmp),
+            [0x76, 0x2F, 0x31, 0x01, ..] => Some(Self::Exr),
+            [b'#', b'?', ..] => Some(Self::Hdr),
+            [b'<', b's', b'v', b'g', ..] => Some(Self::Svg),
+            _ => None,
+        }
+    }
+
+    /// Detect the format of a file by reading its header.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        if is_svg_path(path) {
+            return Some(Self::Svg);
+        }
+        let mut header = [0u8; MAGIC_LEN];
+        let mut file = File::open(path).ok()?;
+        let mut len = 0;
+        while len < MAGIC_LEN {
+            match file.read(&mut header[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(_) => return None,
+            }
+        }
+        Self::from_header(path, &header[..len])
+    }
+
+    /// Detect the format from the leading bytes of the file or archive entry named `path`.
+    pub fn from_header(path: &Path, bytes: &[u8]) -> Option<Self> {
+        if is_svg_path(path) {
+            return Some(Self::Svg);
+        }
+        // NEF, ARW and DNG are TIFF files, told apart by their extension
+        match Self::from_magic(bytes) {
+            Some(Self::Tiff) if crate::raw::is_raw_path(path) => Some(Self::Raw),
+            format => format,
+        }
+    }
+
+    /// Whether exiv2 can read metadata (orientation, thumbnails) from this format.
+    pub fn has_metadata(&self) -> bool {
+        match self {
+            Self::Jpeg | Self::Png | Self::WebP | Self::Tiff | Self::Raw => true,
+            Self::Gif | Self::Bmp | Self::Exr | Self::Hdr | Self::Svg => false,
+        }
+    }
+}
+
+/// SVGs often start with an XML declaration or comments, SVGZ is gzipped.
+fn is_svg_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ["svg", "svgz"].contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+impl TryFrom<FileFormat> for image::ImageFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(format: FileFormat) -> anyhow::Result<Self> {
+        Ok(match format {
+            FileFormat::Jpeg => image::ImageFormat::Jpeg,
+            FileFormat::Png => image::ImageFormat::Png,
+            FileFormat::WebP => image::ImageFormat::WebP,
+            FileFormat::Gif => image::ImageFormat::Gif,
+            FileFormat::Tiff => image::ImageFormat::Tiff,
+            FileFormat::Bmp => image::ImageFormat::Bmp,
+            FileFormat::Exr => image::ImageFormat::OpenExr,
+            FileFormat::Hdr => image::ImageFormat::Hdr,
+            FileFormat::Raw | FileFormat::Svg => {
+                return Err(anyhow::anyhow!("{:?} is not decoded by the image crate", format))
+            }
+        })
+    }
+}
+
+/// Walk the header segments of a JPEG, up to the start of the image data.
+/// `visit` is called with each marker, the offset of its payload in the stream and the payload.
+pub fn read_jpeg_segments(
+    mut reader: impl Read,
+    mut visit: impl FnMut(u8, u64, &[u8]),
+) -> anyhow::Result<()> {
+    let mut soi = [0u8; 2];
+    reader.read_exact(&mut soi)?;
+    if soi != [0xFF, 0xD8] {
+        return Err(anyhow::anyhow!("not a JPEG"));
+    }
+    let mut offset = 2u64;
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        offset += 1;
+        if byte[0] != 0xFF {
+            return Err(anyhow::anyhow!("expected marker, got {:#04x}", byte[0]));
+        }
+        // markers may be preceded by any number of fill bytes
+        let marker = loop {
+            reader.read_exact(&mut byte)?;
+            offset += 1;
+            if byte[0] != 0xFF {
+                break byte[0];
+            }
+        };
+        match marker {
+            // start of scan or end of image, no more metadata
+            0xDA | 0xD9 => return Ok(()),
+            // standalone markers without a length
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+        let mut length = [0u8; 2];
+        reader.read_exact(&mut length)?;
+        offset += 2;
+        let length = u16::from_be_bytes(length) as usize;
+        if length < 2 {
+            return Err(anyhow::anyhow!("invalid segment length {}", length));
+        }
+        let mut payload = vec![0u8; length - 2];
+        reader.read_exact(&mut payload)?;
+        visit(marker, offset, &payload);
+        offset += payload.len() as u64;
+    }
+}
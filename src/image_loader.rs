@@ -1,21 +1,70 @@
-use crate::texture::{ImageResolution, SizedImage};
+use crate::archive::{self, ArchiveEntry};
+use crate::buffer_pool;
+use crate::color::ColorManagement;
+use crate::config::Config;
+use crate::decode_queue::{CancelToken, DecodeQueue, Priority};
+use crate::format::FileFormat;
+use crate::load_error::LoadError;
+use crate::navigation::{Direction, Navigation};
+use crate::raw;
+use crate::scan::ScanOptions;
+use crate::sort::{natural_cmp, SortOrder, Sorter};
+use crate::texture::{ImageResolution, SizedImage, THUMBNAIL_SIZE};
+use crate::thumbnail_cache::ThumbnailCache;
+use crate::wake;
+use crate::watcher::{Change, DirectoryWatcher};
 use anyhow::*;
-use log::{debug, error};
-use std::collections::HashMap;
+use log::{debug, error, warn};
+use std::collections::{HashMap, HashSet};
 use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Thumbnails are preloaded this many times further out than full images,
+/// so that fast navigation still shows something immediately.
+/// Both are limited by the memory budgets.
+const THUMBNAIL_RADIUS_FACTOR: usize = 4;
+
+/// Decoded images are kept this many times further out than textures, so that
+/// browsing back to them needs no decode. Limited by the RAM budget.
+const RAM_RADIUS_FACTOR: usize = 4;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ImageRef {
+    /// For archive entries, the archive path joined with the entry name,
+    /// which identifies the image for sorting, display and `Storage`
     pub path: PathBuf,
+    /// Sub-image of multi-page TIFF and MPO files, 0 for the first
+    pub page: usize,
+    pub entry: Option<ArchiveEntry>,
 }
 
 impl ImageRef {
     fn new(path: PathBuf) -> Self {
-        Self { path }
+        Self {
+            path,
+            page: 0,
+            entry: None,
+        }
+    }
+
+    fn in_archive(entry: ArchiveEntry) -> Self {
+        Self {
+            path: entry.archive.join(&entry.name),
+            page: 0,
+            entry: Some(entry),
+        }
+    }
+
+    fn with_page(&self, page: usize) -> Self {
+        Self {
+            page,
+            ..self.clone()
+        }
     }
 }
 
@@ -34,89 +83,454 @@ impl ImageRequest {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone)]
 enum LoadState {
-    Pending,
+    Pending(CancelToken),
     Loaded,
 }
 
+impl LoadState {
+    /// Stop decoding, called when the request is dropped from the cache.
+    fn cancel(&self) {
+        if let LoadState::Pending(token) = self {
+            token.cancel();
+        }
+    }
+}
+
+/// A decoded image, or why the request failed.
+pub type LoadResult = Result<SizedImage, (ImageRequest, LoadError)>;
+
 pub struct ImageLoader {
     pub images: Vec<ImageRef>,
-    pub sender: Sender<Result<SizedImage>>,
-    pub receiver: Receiver<Result<SizedImage>>,
+    pub sender: Sender<LoadResult>,
+    pub receiver: Receiver<LoadResult>,
+    /// Maximum distance of images preloaded at FULLHD
     pub preload: usize,
+    gpu_budget: usize,
+    ram_budget: usize,
     index: usize,
+    /// Page shown of the current file
+    page: usize,
+    /// Known page counts of multi-page files
+    page_counts: HashMap<PathBuf, usize>,
+    target_size: (u32, u32),
+    /// Viewport zoom, which NATIVE renders of vector images cover
+    zoom: f64,
+    /// Size the current vector image was last requested at NATIVE resolution
+    vector_target: Option<(ImageRef, (u32, u32))>,
     cache: Arc<Mutex<HashMap<ImageRequest, LoadState>>>,
-    thread_pool: rayon::ThreadPool,
+    /// Decoded images, so that textures dropped to stay within the GPU
+    /// budget can be uploaded again without decoding
+    decoded: HashMap<ImageRequest, SizedImage>,
+    /// Sizes of decoded images in bytes, to plan within the budgets
+    byte_sizes: HashMap<ImageRequest, usize>,
+    full_sizes: HashMap<ImageRef, (u32, u32)>,
+    /// Images that failed to load, shown as a placeholder with the reason
+    errors: HashMap<ImageRef, LoadError>,
+    thumbnail_cache: Option<Arc<ThumbnailCache>>,
+    color: Arc<ColorManagement>,
+    queue: DecodeQueue,
+    /// Distance from the current image for preloaded neighbours
+    distances: HashMap<ImageRef, usize>,
+    navigation: Navigation,
+    /// Only thumbnails are loaded while the user moves on faster than FULLHD decodes
+    skimming: bool,
+    /// Time from request to result of recent FULLHD decodes
+    decode_time: Option<Duration>,
+    requested_at: HashMap<ImageRequest, Instant>,
+    watcher: Option<DirectoryWatcher>,
+    /// Watched directories, with the options they were scanned with
+    roots: Vec<(PathBuf, ScanOptions)>,
+    /// Jump to newly added images
+    follow: bool,
+    sorter: Sorter,
 }
 
 impl ImageLoader {
-    pub fn from_paths(paths: Vec<PathBuf>, preload: usize) -> Self {
-        let mut images = Vec::new();
-        for path in paths {
-            images.push(ImageRef::new(path));
-        }
+    pub fn from_paths(paths: Vec<PathBuf>, config: &Config, target_size: (u32, u32)) -> Self {
+        Self::from_refs(paths.into_iter().map(ImageRef::new).collect(), config, target_size)
+    }
+
+    fn from_refs(mut images: Vec<ImageRef>, config: &Config, target_size: (u32, u32)) -> Self {
+        let seed = config.seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0)
+        });
+        let mut sorter = Sorter::new(config.sort, config.reverse, seed);
+        sorter.sort_by_key(&mut images, |iref| &iref.path);
         let (sender, receiver) = channel();
         let num_threads = thread::available_parallelism()
             .unwrap_or(NonZeroUsize::new(2).unwrap())
             .get();
-        debug!("Creating thread pool with {} threads", num_threads);
+        debug!("Starting {} decode threads", num_threads);
         let mut loader = Self {
-            thread_pool: rayon::ThreadPoolBuilder::new()
-                .num_threads(num_threads)
-                .build()
-                .unwrap(),
-            preload,
+            queue: DecodeQueue::new(num_threads),
+            distances: HashMap::new(),
+            navigation: Navigation::new(),
+            skimming: false,
+            decode_time: None,
+            requested_at: HashMap::new(),
+            preload: config.preload,
+            gpu_budget: config.gpu_budget,
+            // idle buffers kept for reuse count towards the budget too
+            ram_budget: buffer_pool::reserve(config.ram_budget),
             cache: Arc::new(Mutex::new(HashMap::new())),
+            decoded: HashMap::new(),
+            byte_sizes: HashMap::new(),
+            full_sizes: HashMap::new(),
+            errors: HashMap::new(),
+            thumbnail_cache: if config.no_thumbnail_cache {
+                None
+            } else {
+                ThumbnailCache::new().map(Arc::new)
+            },
+            color: Arc::new(
+                ColorManagement::new(config.display_profile.as_deref()).unwrap_or_else(|e| {
+                    error!("{}, falling back to sRGB", e);
+                    ColorManagement::new(None).expect("sRGB profile")
+                }),
+            ),
             sender,
             receiver,
             images,
             index: 0,
+            page: 0,
+            page_counts: HashMap::new(),
+            zoom: 1.0,
+            vector_target: None,
+            target_size,
+            watcher: None,
+            roots: Vec::new(),
+            follow: config.follow,
+            sorter,
         };
         if loader.len() > 0 {
             loader.set(0).unwrap();
-            // log and discard error
-            /*
-            loader
-                .load_all_thumbnails()
-                .map_err(|e| error!("Error loading thumbnails: {}", e))
-                .ok();
-
-             */
         }
         loader
     }
 
-    pub fn from_path(path: PathBuf, preload: usize) -> Result<Self> {
+    /// Open a directory, a file within its directory, or the images inside an archive.
+    pub fn from_path(path: PathBuf, config: &Config, target_size: (u32, u32)) -> Result<Self> {
+        if path.is_file() && archive::is_archive_path(&path) {
+            let entries = archive::list_images(&path)?;
+            if entries.is_empty() {
+                return Err(anyhow!("{}: no images in archive", path.display()));
+            }
+            let images = entries.into_iter().map(ImageRef::in_archive).collect();
+            return Ok(Self::from_refs(images, config, target_size));
+        }
         if path.is_file() {
-            let mut dir = path.clone();
-            dir.pop();
-            let mut loader = Self::from_path(dir, preload)?;
-            loader.set(loader.images.iter().position(|p| p.path == path).unwrap())?;
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            };
+            // scanned paths are prefixed with `dir`
+            let path = dir.join(path.file_name().ok_or(anyhow!("invalid path"))?);
+            let mut loader = Self::from_path(dir, config, target_size)?;
+            let index = loader
+                .images
+                .iter()
+                .position(|p| p.path == path)
+                .ok_or(anyhow!("{}: not a supported image or excluded", path.display()))?;
+            loader.set(index)?;
             return Ok(loader);
         }
 
-        let mut paths = Vec::new();
-        for entry in std::fs::read_dir(path.clone())
-            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?
-        {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            let is_jpeg = path
-                .extension()
-                .map(|e| e.to_ascii_lowercase())
-                .map(|e| e == "jpg" || e == "jpeg")
-                .unwrap_or(false);
-            if path.is_file() && is_jpeg {
-                paths.push(path)
+        let options = ScanOptions::from_config(config)?;
+        let paths = options.scan_dir(&path)?;
+        let mut loader = Self::from_paths(paths, config, target_size);
+        if !config.no_watch {
+            loader
+                .watch(path, options)
+                .map_err(|e| warn!("Not watching for changes: {}", e))
+                .ok();
+        }
+        Ok(loader)
+    }
+
+    /// Create a loader for the paths given on the command line.
+    /// A single file opens its directory at that file; otherwise directories
+    /// are scanned, and with `--sort none` files are shown in the order given.
+    /// Archives (ZIP, CBZ, TAR, CBT) contribute their image entries.
+    pub fn from_config(config: &Config, target_size: (u32, u32)) -> Result<Self> {
+        if config.browse_parent {
+            return Self::from_path(config.paths[0].clone(), config, target_size);
+        }
+        let options = ScanOptions::from_config(config)?;
+        let mut images = Vec::new();
+        let mut dirs = Vec::new();
+        for path in &config.paths {
+            if path.is_dir() {
+                match options.scan_dir(path) {
+                    Result::Ok(scanned) => images.extend(scanned.into_iter().map(ImageRef::new)),
+                    Err(e) => warn!("{}", e),
+                }
+                dirs.push(path.clone());
+            } else if archive::is_archive_path(path) {
+                match archive::list_images(path) {
+                    Result::Ok(entries) => images.extend(entries.into_iter().map(ImageRef::in_archive)),
+                    Err(e) => warn!("{}", e),
+                }
+            } else if FileFormat::from_path(path).is_some() {
+                images.push(ImageRef::new(path.clone()));
+            } else {
+                warn!("{}: not a supported image", path.display());
+            }
+        }
+        let mut seen = HashSet::new();
+        images.retain(|iref| seen.insert(iref.clone()));
+        let mut loader = Self::from_refs(images, config, target_size);
+        if !config.no_watch {
+            for dir in dirs {
+                loader
+                    .watch(dir, options.clone())
+                    .map_err(|e| warn!("Not watching for changes: {}", e))
+                    .ok();
+            }
+        }
+        Ok(loader)
+    }
+
+    /// Watch `dir` for added, removed and renamed images.
+    fn watch(&mut self, dir: PathBuf, options: ScanOptions) -> Result<()> {
+        let watcher = match &mut self.watcher {
+            Some(watcher) => watcher,
+            None => self.watcher.insert(DirectoryWatcher::new()?),
+        };
+        watcher.watch(&dir, options.max_depth > 1)?;
+        self.roots.push((dir, options));
+        Ok(())
+    }
+
+    fn root_for(&self, path: &Path) -> Option<&(PathBuf, ScanOptions)> {
+        self.roots.iter().find(|(root, _)| path.starts_with(root))
+    }
+
+    /// Images at or below a path that appeared in a watched directory.
+    fn scan_added(&self, path: &Path) -> Vec<PathBuf> {
+        let (root, options) = match self.root_for(path) {
+            Some(root) => root,
+            None => return Vec::new(),
+        };
+        if path.is_dir() {
+            options.scan_subdir(root, path).unwrap_or_else(|e| {
+                warn!("{}", e);
+                Vec::new()
+            })
+        } else if options.accepts(root, path) {
+            vec![path.to_path_buf()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Insert an image at its sorted position. Without a sort order, it is
+    /// placed among the images of the same watched directory.
+    /// Returns false if already present.
+    fn insert(&mut self, path: PathBuf) -> bool {
+        if self.images.iter().any(|iref| iref.path == path) {
+            return false;
+        }
+        let root = match self.root_for(&path) {
+            Some((root, _)) => root.clone(),
+            None => return false,
+        };
+        let sorted = self
+            .sorter
+            .position(self.images.iter().map(|iref| &iref.path), &path);
+        let in_root = |iref: &ImageRef| iref.path.starts_with(&root);
+        let pos = sorted
+            .or_else(|| {
+                self.images
+                    .iter()
+                    .position(|iref| in_root(iref) && natural_cmp(&iref.path, &path).is_gt())
+            })
+            .or_else(|| self.images.iter().rposition(in_root).map(|pos| pos + 1))
+            .unwrap_or(self.images.len());
+        self.images.insert(pos, ImageRef::new(path));
+        true
+    }
+
+    /// Forget all cached data of an image, for all of its pages.
+    fn invalidate(&mut self, iref: &ImageRef) {
+        self.sorter.forget(&iref.path);
+        self.cache.lock().unwrap().retain(|req, state| {
+            let keep = req.reference.path != iref.path;
+            if !keep {
+                state.cancel();
             }
+            keep
+        });
+        self.decoded.retain(|req, _| req.reference.path != iref.path);
+        self.byte_sizes.retain(|req, _| req.reference.path != iref.path);
+        self.full_sizes.retain(|r, _| r.path != iref.path);
+        self.errors.retain(|r, _| r.path != iref.path);
+        self.page_counts.remove(&iref.path);
+    }
+
+    /// Remove the image at `path`, or all images below it if it was a directory.
+    fn remove(&mut self, path: &Path) -> Vec<ImageRef> {
+        let (removed, kept) = std::mem::take(&mut self.images)
+            .into_iter()
+            .partition(|iref| iref.path.starts_with(path));
+        self.images = kept;
+        for iref in &removed {
+            self.invalidate(iref);
+        }
+        removed
+    }
+
+    /// A JPEG written after its RAW file takes the RAW file's place in the sequence.
+    fn replace_raw_partners(
+        &mut self,
+        jpeg_path: &Path,
+        current: &mut Option<ImageRef>,
+    ) -> Vec<ImageRef> {
+        let mut removed = Vec::new();
+        for raw_path in raw::raw_partners(jpeg_path) {
+            removed.extend(self.remove(&raw_path));
+        }
+        if current.as_ref().is_some_and(|c| removed.contains(c)) {
+            *current = Some(ImageRef::new(jpeg_path.to_path_buf()));
+        }
+        removed
+    }
+
+    /// Apply changes in watched directories to the image list.
+    /// The current image stays selected unless it was removed, or a new image
+    /// appeared in follow mode. Returns images whose cached data is now invalid.
+    pub fn poll_changes(&mut self) -> Vec<ImageRef> {
+        let changes = match &mut self.watcher {
+            Some(watcher) => watcher.poll(),
+            None => return Vec::new(),
+        };
+        if changes.is_empty() {
+            return Vec::new();
+        }
+        let mut current = self.images.get(self.index).cloned();
+        let shown = current.clone();
+        let mut invalidated = Vec::new();
+        let mut newest = None;
+        for change in changes {
+            debug!("file change: {:?}", change);
+            match change {
+                Change::Added(path) => {
+                    for path in self.scan_added(&path) {
+                        if self.insert(path.clone()) {
+                            invalidated.extend(self.replace_raw_partners(&path, &mut current));
+                            newest = Some(path);
+                        }
+                    }
+                }
+                Change::Removed(path) => invalidated.extend(self.remove(&path)),
+                Change::Modified(path) => {
+                    let iref = ImageRef::new(path.clone());
+                    if self.images.contains(&iref) {
+                        self.invalidate(&iref);
+                        invalidated.push(iref);
+                    } else if self.scan_added(&path).contains(&path) && self.insert(path.clone()) {
+                        // files are only complete once the writer closes them
+                        invalidated.extend(self.replace_raw_partners(&path, &mut current));
+                        newest = Some(path);
+                    }
+                }
+                Change::Renamed(from, to) => {
+                    invalidated.extend(self.remove(&from));
+                    // writers often finish a temporary file, then rename it into place
+                    for path in self.scan_added(&to) {
+                        if self.insert(path.clone()) {
+                            invalidated.extend(self.replace_raw_partners(&path, &mut current));
+                            newest = Some(path);
+                        }
+                    }
+                    // keep the renamed image (or an image in a renamed directory) selected
+                    if let Some(iref) = &current {
+                        if let Result::Ok(relative) = iref.path.strip_prefix(&from) {
+                            let path = if relative.as_os_str().is_empty() {
+                                to.clone()
+                            } else {
+                                to.join(relative)
+                            };
+                            current = Some(ImageRef::new(path));
+                        }
+                    }
+                }
+            }
+        }
+        if self.follow {
+            if let Some(newest) = newest {
+                current = Some(ImageRef::new(newest));
+            }
+        }
+        // the page stays selected while the file is unchanged
+        let page = match &current {
+            Some(c) if shown.as_ref() == Some(c) && !invalidated.contains(c) => self.page,
+            _ => 0,
+        };
+        let index = current
+            .and_then(|current| self.images.iter().position(|iref| *iref == current))
+            .unwrap_or_else(|| self.index.min(self.len().saturating_sub(1)));
+        if self.images.is_empty() {
+            self.index = 0;
+            self.page = 0;
+        } else {
+            self.select(index, page)
+                .map_err(|e| error!("Error selecting image: {}", e))
+                .ok();
+        }
+        invalidated
+    }
+
+    pub fn sort_order(&self) -> (SortOrder, bool) {
+        (self.sorter.order, self.sorter.reverse)
+    }
+
+    /// Re-sort the images, keeping the current image selected.
+    pub fn set_sort_order(&mut self, order: SortOrder, reverse: bool) {
+        let current = self.images.get(self.index).cloned();
+        self.sorter.set_order(order, reverse);
+        self.sorter.sort_by_key(&mut self.images, |iref| &iref.path);
+        if let Some(index) = current.and_then(|c| self.images.iter().position(|i| *i == c)) {
+            self.select(index, self.page)
+                .map_err(|e| error!("Error selecting image: {}", e))
+                .ok();
         }
-        paths.sort();
-        Ok(Self::from_paths(paths, preload))
     }
 
-    pub fn current(&self) -> ImageRef {
-        self.get(self.index).unwrap().clone()
+    /// The current image, at the page shown. `None` while there are no images.
+    pub fn current(&self) -> Option<ImageRef> {
+        self.images.get(self.index).map(|iref| iref.with_page(self.page))
+    }
+
+    /// Why the image could not be loaded, if it failed.
+    pub fn error(&self, iref: &ImageRef) -> Option<&LoadError> {
+        self.errors.get(iref)
+    }
+
+    /// The page shown of the current file and its page count, once known.
+    pub fn page(&self) -> (usize, Option<usize>) {
+        let count = self
+            .images
+            .get(self.index)
+            .and_then(|iref| self.page_counts.get(&iref.path))
+            .copied();
+        (self.page, count)
+    }
+
+    /// Show the next or previous page of the current file. Returns false at
+    /// the first or last page, and while the page count is not known yet.
+    pub fn step_page(&mut self, forward: bool) -> Result<bool> {
+        let page = match self.page() {
+            (page, Some(count)) if forward && page + 1 < count => page + 1,
+            (page, Some(_)) if !forward && page > 0 => page - 1,
+            _ => return Ok(false),
+        };
+        self.select(self.index, page)?;
+        Ok(true)
     }
 
     pub fn get(&self, index: usize) -> Result<&ImageRef> {
@@ -129,99 +543,367 @@ impl ImageLoader {
         self.images.len()
     }
 
+    /// Show the first page of the image at `index`.
     pub fn set(&mut self, index: usize) -> Result<()> {
+        self.select(index, 0)
+    }
+
+    fn select(&mut self, index: usize, page: usize) -> Result<()> {
+        let iref = self.get(index)?.with_page(page);
         self.index = index;
-        self.request_image(&ImageRequest::new(
-            self.get(index)?.clone(),
-            ImageResolution::NATIVE,
-        ));
+        self.page = page;
+        self.skimming = self.is_skimming();
+        self.distances = self
+            .neighbours(self.preload_radius())
+            .into_iter()
+            .map(|(distance, iref)| (iref, distance))
+            .collect();
+        // cancel work for images that moved out of reach, run the rest nearest first
+        self.clear_cache();
+        self.queue.reprioritize(|req| self.priority(req));
+        // the thumbnail is shown (scaled up) until the full image has been decoded
+        self.preload();
+        debug!("showing {:?}", iref);
         Ok(())
     }
 
-    pub fn preload(&mut self, preload: usize) -> Result<()> {
-        for iref in self.get_radius(preload) {
-            self.request_image(&ImageRequest::new(iref.clone(), ImageResolution::NATIVE));
+    fn is_skimming(&self) -> bool {
+        self.decode_time
+            .is_some_and(|decode_time| self.navigation.is_faster_than(decode_time))
+    }
+
+    /// When `update_skimming` or `poll_changes` may have something to do
+    /// without being woken up for it.
+    pub fn deadline(&self) -> Option<Instant> {
+        let skimming_end = match self.skimming {
+            true => self.decode_time.and_then(|t| self.navigation.pause_deadline(t)),
+            false => None,
+        };
+        let renames = self.watcher.as_ref().and_then(|w| w.deadline());
+        skimming_end.into_iter().chain(renames).min()
+    }
+
+    /// Re-plan when skimming starts or stops, so that the current image is
+    /// loaded at FULLHD once the user pauses.
+    pub fn update_skimming(&mut self) {
+        let skimming = self.is_skimming();
+        if skimming != self.skimming {
+            debug!("skimming: {}", skimming);
+            self.skimming = skimming;
+            self.clear_cache();
+            self.preload();
         }
+    }
 
-        Ok(())
+    /// Request the current image at native resolution, e.g. when zooming in
+    /// beyond what the FULLHD decode provides. Nothing happens if it would
+    /// not fit into the GPU budget.
+    pub fn request_native(&mut self) {
+        let Some(current) = self.current() else {
+            return;
+        };
+        if !self.native_fits(&current) {
+            return;
+        }
+        self.request_image(&ImageRequest::new(current, ImageResolution::NATIVE));
+    }
+
+    /// Render the current vector image again to cover the window at `zoom`,
+    /// replacing an earlier NATIVE render of a different size.
+    pub fn request_vector_render(&mut self, zoom: f64) {
+        self.zoom = zoom;
+        let Some(current) = self.current() else {
+            return;
+        };
+        let target = self.zoomed_target_size();
+        let req = ImageRequest::new(current.clone(), ImageResolution::NATIVE);
+        let loaded = match self.cache.lock().unwrap().get(&req) {
+            // a render in progress finishes first, as rasterising cannot be stopped;
+            // the redraw after it arrives requests the size zoomed to by then
+            Some(LoadState::Pending(_)) => return,
+            Some(LoadState::Loaded) => true,
+            None => false,
+        };
+        if loaded && self.vector_target == Some((current.clone(), target)) {
+            return;
+        }
+        debug!("rendering {:?} at {:?}", current, target);
+        if let Some(state) = self.cache.lock().unwrap().remove(&req) {
+            state.cancel();
+        }
+        self.decoded.remove(&req);
+        self.byte_sizes.remove(&req);
+        self.vector_target = Some((current, target));
+        self.request_image(&req);
+    }
+
+    /// The window size scaled by the viewport zoom. Limited to half the GPU
+    /// budget, beyond that vector images are magnified like other images.
+    fn zoomed_target_size(&self) -> (u32, u32) {
+        let width = self.target_size.0 as f64 * self.zoom;
+        let height = self.target_size.1 as f64 * self.zoom;
+        let max_pixels = (self.gpu_pixel_budget() / 4 / 2) as f64;
+        let shrink = (max_pixels / (width * height)).sqrt().min(1.0);
+        ((width * shrink) as u32, (height * shrink) as u32)
+    }
+
+    /// Set the window size that FULLHD decodes must cover.
+    pub fn set_target_size(&mut self, target_size: (u32, u32)) {
+        self.target_size = target_size;
+    }
+
+    /// Pixel bytes the GPU budget allows, mipmaps add a third to each texture.
+    fn gpu_pixel_budget(&self) -> usize {
+        self.gpu_budget / 4 * 3
+    }
+
+    /// Request everything the GPU budget allows around the current image.
+    pub fn preload(&mut self) {
+        for req in self.plan(self.gpu_pixel_budget(), self.preload) {
+            self.request_image(&req);
+        }
     }
 
     pub fn next_image(&mut self) -> Result<()> {
+        if self.images.is_empty() {
+            return Ok(());
+        }
+        self.navigation.step(Direction::Forward);
         self.set((self.index + 1) % self.len())?;
         Ok(())
     }
 
     pub fn prev_image(&mut self) -> Result<()> {
+        if self.images.is_empty() {
+            return Ok(());
+        }
+        self.navigation.step(Direction::Backward);
         self.set((self.index + self.len() - 1) % self.len())?;
         Ok(())
     }
 
+    /// The current image runs first, then its neighbours by distance;
+    /// at equal distance smaller resolutions run first.
+    pub fn priority(&self, req: &ImageRequest) -> Priority {
+        let distance = self
+            .distances
+            .get(&req.reference)
+            .copied()
+            .unwrap_or(usize::MAX);
+        (distance, req.resolution)
+    }
+
     pub fn request_image(&mut self, req: &ImageRequest) {
         let mut cache = self.cache.lock().unwrap();
         if cache.get(req).is_some() {
             // already requested
             return;
         }
-        let n_pending = cache
-            .iter()
-            .filter(|(req, state)| {
-                **state == LoadState::Pending && req.resolution != ImageResolution::THUMBNAIL
-            })
-            .count();
-        cache.insert(req.clone(), LoadState::Pending);
+        if let Some(image) = self.decoded.get(req) {
+            // the texture was dropped, but the decoded image is still around
+            cache.insert(req.clone(), LoadState::Loaded);
+            self.sender.send(Result::Ok(image.clone())).ok();
+            wake::wake();
+            return;
+        }
+        let token = CancelToken::default();
+        cache.insert(req.clone(), LoadState::Pending(token.clone()));
+        drop(cache);
+        if req.resolution == ImageResolution::FULLHD {
+            self.requested_at.insert(req.clone(), Instant::now());
+        }
         let sender = self.sender.clone();
-        let req = req.clone();
         let cache = Arc::clone(&self.cache);
-        self.thread_pool.spawn(move || {
-            // debounce to avoid spamming the thread pool
-            // if user is scrolling quickly, the requests might be expired after this delay
-            if n_pending > 0 {
-                let delay_ms = if n_pending > 5 { 100 } else { 10 };
-                thread::sleep(std::time::Duration::from_millis(delay_ms));
-            }
-            if cache.lock().unwrap().get(&req).is_none() {
+        let target_size = match req.resolution {
+            ImageResolution::NATIVE => self.zoomed_target_size(),
+            _ => self.target_size,
+        };
+        let thumbnail_cache = self.thumbnail_cache.clone();
+        let color = Arc::clone(&self.color);
+        let priority = self.priority(req);
+        let ram_budget = self.ram_budget;
+        let job_req = req.clone();
+        self.queue.push(req.clone(), priority, token, move |cancel| {
+            let req = job_req;
+            let decode = || {
+                SizedImage::from_request(
+                    req.clone(),
+                    target_size,
+                    ram_budget,
+                    thumbnail_cache.as_deref(),
+                    &color,
+                    cancel,
+                )
+            };
+            // a decoder that panics fails the request, which then shows the error placeholder
+            let sized_image = catch_unwind(AssertUnwindSafe(decode))
+                .unwrap_or_else(|_| Err(LoadError::Corrupt("decoder panicked".to_string())))
+                .map_err(|e| (req.clone(), e));
+            if cancel.is_cancelled() {
+                debug!("cancelled: {:?}", req);
                 return;
             }
-            let sized_image = SizedImage::from_request(req.clone());
             if let Err(SendError(_)) = sender.send(sized_image) {
                 debug!("send error: {:?}", req);
                 return;
             }
-            cache.lock().unwrap().insert(req, LoadState::Loaded);
+            // the request may have been invalidated while decoding
+            if let Some(state) = cache.lock().unwrap().get_mut(&req) {
+                *state = LoadState::Loaded;
+            }
+            wake::wake();
         })
     }
 
-    pub fn load_all_thumbnails(&mut self) -> Result<()> {
-        for i in 0..self.len() {
-            let iref = self.get(i)?.clone();
-            self.request_image(&ImageRequest::new(iref, ImageResolution::THUMBNAIL));
+    /// How far out thumbnails are preloaded at most.
+    fn preload_radius(&self) -> usize {
+        self.preload.max(1) * THUMBNAIL_RADIUS_FACTOR
+    }
+
+    /// Images ordered by distance from the current one, up to `radius`,
+    /// with their distance. Distances are scaled by how far the window
+    /// reaches in each direction, so that browsing forward preloads more
+    /// images ahead than behind.
+    fn neighbours(&self, radius: usize) -> Vec<(usize, ImageRef)> {
+        let len = self.len();
+        let (ahead, behind) = self.navigation.reach();
+        let (next_reach, prev_reach) = match self.navigation.direction() {
+            Some(Direction::Backward) => (behind, ahead),
+            _ => (ahead, behind),
+        };
+        let mut candidates = vec![(0, 0)];
+        for (reach, sign) in [(next_reach, 1), (prev_reach, -1)] {
+            for steps in 1..len {
+                let distance = (steps * 100).div_ceil(reach);
+                if distance > radius {
+                    break;
+                }
+                candidates.push((distance, sign * steps as isize));
+            }
         }
-        Ok(())
+        candidates.sort_by_key(|(distance, offset)| (*distance, offset.unsigned_abs()));
+        let mut seen = HashSet::new();
+        candidates
+            .into_iter()
+            .filter_map(|(distance, offset)| {
+                let index = (self.index as isize + offset).rem_euclid(len as isize) as usize;
+                let iref = match offset {
+                    0 => self.images[index].with_page(self.page),
+                    _ => self.images[index].clone(),
+                };
+                seen.insert(index).then_some((distance, iref))
+            })
+            .collect()
+    }
+
+    /// Size of the decoded image for `req` in bytes. Exact once it has been
+    /// loaded, before that it is estimated from the window size.
+    fn byte_size(&self, req: &ImageRequest) -> usize {
+        if let Some(size) = self.byte_sizes.get(req) {
+            return *size;
+        }
+        let target = self.target_size.0 as usize * self.target_size.1 as usize;
+        let full = self
+            .full_sizes
+            .get(&req.reference)
+            .map(|(w, h)| *w as usize * *h as usize);
+        let pixels = match req.resolution {
+            ImageResolution::THUMBNAIL => (THUMBNAIL_SIZE * THUMBNAIL_SIZE) as usize,
+            ImageResolution::FULLHD => full.map_or(target, |full| full.min(target)),
+            ImageResolution::NATIVE => full.unwrap_or(target * 4),
+        };
+        pixels * 4
     }
 
-    fn get_offset(&self, offset: isize) -> Result<&ImageRef> {
-        let index = (self.len() as isize + self.index as isize + offset) % self.len() as isize;
-        self.get(index as usize)
+    fn native_fits(&self, iref: &ImageRef) -> bool {
+        let total: usize = [
+            ImageResolution::THUMBNAIL,
+            ImageResolution::FULLHD,
+            ImageResolution::NATIVE,
+        ]
+        .into_iter()
+        .map(|resolution| self.byte_size(&ImageRequest::new(iref.clone(), resolution)))
+        .sum();
+        total <= self.gpu_pixel_budget()
     }
 
-    fn get_radius(&self, radius: usize) -> Vec<ImageRef> {
-        let radius = radius as isize;
-        let mut irefs = vec![self.get_offset(0).unwrap().clone()];
-        for i in 1..=radius {
-            for iref in [self.get_offset(i), self.get_offset(-i)] {
-                if let Result::Ok(iref) = iref {
-                    irefs.push(iref.clone());
+    /// Requests to keep loaded within `budget` bytes. The current image is
+    /// always loaded, at native resolution if requested and it fits.
+    /// Neighbours up to distance `preload` are added nearest first, and once
+    /// FULLHD no longer fits the remaining ones are downgraded to THUMBNAIL,
+    /// then dropped entirely.
+    fn plan(&self, budget: usize, preload: usize) -> Vec<ImageRequest> {
+        let mut plan = Vec::new();
+        let Some(current) = self.current() else {
+            return plan;
+        };
+        let native = ImageRequest::new(current.clone(), ImageResolution::NATIVE);
+        let wants_native = self.cache.lock().unwrap().contains_key(&native);
+        plan.push(ImageRequest::new(current.clone(), ImageResolution::THUMBNAIL));
+        if !self.skimming {
+            plan.push(ImageRequest::new(current.clone(), ImageResolution::FULLHD));
+            if wants_native && self.native_fits(&current) {
+                plan.push(native);
+            }
+        }
+        let mut used: usize = plan.iter().map(|req| self.byte_size(req)).sum();
+
+        let (mut level, mut full_radius) = if self.skimming {
+            (Some(ImageResolution::THUMBNAIL), preload)
+        } else {
+            (Some(ImageResolution::FULLHD), 0)
+        };
+        let radius = preload.max(1) * THUMBNAIL_RADIUS_FACTOR;
+        for (distance, iref) in self.neighbours(radius).into_iter().skip(1) {
+            if level == Some(ImageResolution::FULLHD) && distance > preload {
+                level = Some(ImageResolution::THUMBNAIL);
+            }
+            if distance > full_radius.max(1) * THUMBNAIL_RADIUS_FACTOR {
+                break;
+            }
+            while let Some(resolution) = level {
+                let mut reqs = vec![ImageRequest::new(iref.clone(), ImageResolution::THUMBNAIL)];
+                if resolution == ImageResolution::FULLHD {
+                    reqs.push(ImageRequest::new(iref.clone(), ImageResolution::FULLHD));
                 }
+                let size: usize = reqs.iter().map(|req| self.byte_size(req)).sum();
+                if used + size <= budget {
+                    used += size;
+                    plan.extend(reqs);
+                    if resolution == ImageResolution::FULLHD {
+                        full_radius = distance;
+                    }
+                    break;
+                }
+                level = match resolution {
+                    ImageResolution::FULLHD => Some(ImageResolution::THUMBNAIL),
+                    _ => None,
+                };
+            }
+            if level.is_none() {
+                break;
             }
         }
-        irefs
+        plan
     }
 
+    /// Drop requests outside the GPU budget, and decoded images outside the
+    /// RAM budget, which is planned over a wider radius.
     pub fn clear_cache(&mut self) {
-        let keep = self.get_radius(self.preload);
-        self.cache.lock().unwrap().retain(|req, _| {
-            keep.contains(&&req.reference) || req.resolution == ImageResolution::THUMBNAIL
+        let keep: HashSet<ImageRequest> =
+            self.plan(self.gpu_pixel_budget(), self.preload).into_iter().collect();
+        self.cache.lock().unwrap().retain(|req, state| {
+            let keep = keep.contains(req);
+            if !keep {
+                state.cancel();
+            }
+            keep
         });
+        self.requested_at.retain(|req, _| keep.contains(req));
+        let ram_radius = self.preload.max(1) * RAM_RADIUS_FACTOR;
+        let keep_decoded: HashSet<ImageRequest> =
+            self.plan(self.ram_budget, ram_radius).into_iter().collect();
+        self.decoded.retain(|req, _| keep_decoded.contains(req));
     }
 
     pub fn cached(&self) -> Vec<ImageRequest> {
@@ -232,10 +914,90 @@ impl ImageLoader {
         let mut images = Vec::new();
         while let Result::Ok(res) = self.receiver.try_recv().map_err(|e| anyhow!(e)) {
             match res {
-                Result::Ok(image) => images.push(image),
-                Err(e) => error!("Error loading image: {}", e),
+                Result::Ok(image) => {
+                    let req = ImageRequest::new(image.image_ref.clone(), image.resolution);
+                    if let Some(requested_at) = self.requested_at.remove(&req) {
+                        let elapsed = requested_at.elapsed();
+                        self.decode_time = Some(match self.decode_time {
+                            Some(average) => (average * 4 + elapsed) / 5,
+                            None => elapsed,
+                        });
+                    }
+                    self.byte_sizes.insert(req.clone(), image.byte_size());
+                    self.full_sizes.insert(image.image_ref.clone(), image.full_size);
+                    if let Some(count) = image.page_count {
+                        self.page_counts.insert(image.image_ref.path.clone(), count);
+                    }
+                    self.errors.remove(&image.image_ref);
+                    self.decoded.insert(req, image.clone());
+                    images.push(image)
+                }
+                Err((_, LoadError::Cancelled)) => {}
+                Err((req, e)) => {
+                    error!("Error loading {}: {}", req.reference.path.display(), e);
+                    self.requested_at.remove(&req);
+                    self.errors.entry(req.reference).or_insert(e);
+                }
             }
         }
         images
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// A loader for `count` images that are never decoded, preloading one on each side.
+    fn loader(count: usize) -> ImageLoader {
+        let config = Config::parse_from(["vrr", "--no-thumbnail-cache", "--preload", "1"]);
+        let mut loader = ImageLoader::from_paths(Vec::new(), &config, (1000, 1000));
+        loader.images = (0..count)
+            .map(|i| ImageRef::new(PathBuf::from(format!("{}.jpg", i))))
+            .collect();
+        loader
+    }
+
+    fn neighbours(loader: &ImageLoader, radius: usize) -> Vec<(usize, usize)> {
+        loader
+            .neighbours(radius)
+            .into_iter()
+            .map(|(distance, iref)| {
+                (distance, loader.images.iter().position(|i| *i == iref).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn neighbours_alternate_around_the_current_image() {
+        let mut loader = loader(10);
+        assert_eq!(neighbours(&loader, 2), [(0, 0), (1, 1), (1, 9), (2, 2), (2, 8)]);
+        // browsing forward reaches further ahead than behind
+        loader.navigation.step(Direction::Forward);
+        assert_eq!(neighbours(&loader, 2), [(0, 0), (1, 1), (2, 9), (2, 2), (2, 3)]);
+        // a short list wraps around without repeating images
+        assert_eq!(neighbours(&self::loader(3), 4), [(0, 0), (1, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn plan_downgrades_neighbours_to_thumbnails_within_the_budget() {
+        let mut loader = loader(20);
+        let image = |i: usize| loader.images[i].clone();
+        let thumbnail = |i| ImageRequest::new(image(i), ImageResolution::THUMBNAIL);
+        let fullhd = |i| ImageRequest::new(image(i), ImageResolution::FULLHD);
+        let t = loader.byte_size(&thumbnail(0));
+        let f = loader.byte_size(&fullhd(0));
+        assert!(f > 2 * t);
+        // the nearest neighbour gets FULLHD, then only thumbnails fit
+        let plan = loader.plan(2 * (t + f) + 3 * t, 1);
+        let expected = [thumbnail(0), fullhd(0), thumbnail(1), fullhd(1)];
+        let expected = expected.into_iter().chain([thumbnail(19), thumbnail(2), thumbnail(18)]);
+        assert_eq!(plan, expected.collect::<Vec<_>>());
+        // while skimming, even the current image is only a thumbnail
+        loader.skimming = true;
+        let plan = loader.plan(usize::MAX, 1);
+        assert_eq!(plan.len(), 9);
+        assert!(plan.iter().all(|req| req.resolution == ImageResolution::THUMBNAIL));
+    }
+}
@@ -2,8 +2,8 @@ use crate::viewer::Viewer;
 use log::trace;
 
 use winit::event::{
-    DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, TouchPhase,
-    VirtualKeyCode, WindowEvent,
+    DeviceEvent, ElementState, Event, KeyboardInput, ModifiersState, MouseButton,
+    MouseScrollDelta, TouchPhase, VirtualKeyCode, WindowEvent,
 };
 use winit::event_loop::ControlFlow;
 use winit::window::Window;
@@ -12,6 +12,7 @@ use crate::storage::TAG_STARRED;
 pub struct Inputs {
     mouse_pos: Option<(f64, f64)>,
     mouse_down: bool,
+    modifiers: ModifiersState,
 }
 
 impl Inputs {
@@ -19,6 +20,7 @@ impl Inputs {
         Self {
             mouse_pos: None,
             mouse_down: false,
+            modifiers: ModifiersState::empty(),
         }
     }
 }
@@ -31,16 +33,81 @@ async fn on_key_press(
     trace!("Key pressed: {:?}", k);
     let result = match k {
         VirtualKeyCode::Escape | VirtualKeyCode::Q => return Some(ControlFlow::Exit),
+        VirtualKeyCode::J if viewer.inputs.modifiers.shift() => viewer.step_page(true),
+        VirtualKeyCode::K if viewer.inputs.modifiers.shift() => viewer.step_page(false),
         VirtualKeyCode::J => viewer.loader.next_image(),
         VirtualKeyCode::K => viewer.loader.prev_image(),
+        VirtualKeyCode::PageDown => viewer.step_page(true),
+        VirtualKeyCode::PageUp => viewer.step_page(false),
         VirtualKeyCode::F => viewer.resize_fullscreen(window),
+        VirtualKeyCode::Back => {
+            viewer.open_parent();
+            Ok(())
+        }
+        VirtualKeyCode::Key1
+        | VirtualKeyCode::Key2
+        | VirtualKeyCode::Key3
+        | VirtualKeyCode::Key4
+        | VirtualKeyCode::Key5
+        | VirtualKeyCode::Key6
+        | VirtualKeyCode::Key7
+        | VirtualKeyCode::Key8
+        | VirtualKeyCode::Key9 => {
+            // the key codes of the digits are consecutive
+            viewer.open_subdirectory(*k as usize - VirtualKeyCode::Key1 as usize);
+            Ok(())
+        }
         VirtualKeyCode::M => {
-            viewer.storage.entry(&viewer.loader.current()).toggle_tag(TAG_STARRED.to_string());
+            if let Some(iref) = viewer.loader.current() {
+                viewer.storage.entry(&iref).toggle_tag(TAG_STARRED.to_string());
+            }
             viewer.storage.save().map_err(|e| {
                 log::error!("Error saving storage: {}", e);
             }).ok();
             Ok(())
         },
+        VirtualKeyCode::S => {
+            let (order, reverse) = viewer.loader.sort_order();
+            if viewer.inputs.modifiers.shift() {
+                viewer.set_sort_order(order, !reverse);
+            } else {
+                viewer.set_sort_order(order.next(), reverse);
+            }
+            Ok(())
+        }
+        VirtualKeyCode::I => {
+            viewer.set_filter(viewer.rendering.filter.next());
+            Ok(())
+        }
+        VirtualKeyCode::E => {
+            let step = if viewer.inputs.modifiers.shift() { -0.5 } else { 0.5 };
+            viewer.set_exposure(viewer.rendering.exposure + step);
+            Ok(())
+        }
+        VirtualKeyCode::T => {
+            viewer.set_tone_mapping(viewer.rendering.tone_mapping.next());
+            Ok(())
+        }
+        VirtualKeyCode::Space => {
+            viewer.toggle_playback();
+            Ok(())
+        }
+        VirtualKeyCode::Period => {
+            viewer.step_frame(true);
+            Ok(())
+        }
+        VirtualKeyCode::Comma => {
+            viewer.step_frame(false);
+            Ok(())
+        }
+        VirtualKeyCode::RBracket => {
+            viewer.change_playback_speed(true);
+            Ok(())
+        }
+        VirtualKeyCode::LBracket => {
+            viewer.change_playback_speed(false);
+            Ok(())
+        }
         VirtualKeyCode::Minus => Ok(()),
         VirtualKeyCode::Plus => Ok(()),
         VirtualKeyCode::Equals => Ok(()),
@@ -141,6 +208,7 @@ pub async fn on_event<'a>(
             ref event,
             window_id,
         } if window_id == window.id() => {
+            viewer.request_redraw();
             match event {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                 WindowEvent::MouseInput {
@@ -173,6 +241,12 @@ pub async fn on_event<'a>(
                         *control_flow = f;
                     }
                 }
+                WindowEvent::DroppedFile(path) => {
+                    viewer.open(path.clone());
+                }
+                WindowEvent::ModifiersChanged(modifiers) => {
+                    viewer.inputs.modifiers = *modifiers;
+                }
                 WindowEvent::Resized(physical_size) => {
                     viewer.resize(*physical_size);
                 }
@@ -205,9 +279,18 @@ pub async fn on_event<'a>(
             }
         }
         Event::MainEventsCleared => {
-            // RedrawRequested will only trigger once, unless we manually
-            // request it.
-            window.request_redraw();
+            // only draw when something changed, and sleep until the next deadline
+            // or until a decoded image or file change wakes the loop
+            let deadline = viewer.update();
+            if viewer.take_redraw() {
+                window.request_redraw();
+            }
+            if *control_flow != ControlFlow::Exit {
+                *control_flow = match deadline {
+                    Some(deadline) => ControlFlow::WaitUntil(deadline),
+                    None => ControlFlow::Wait,
+                };
+            }
         }
         Event::RedrawEventsCleared | Event::NewEvents(_) => {}
         Event::DeviceEvent { event, .. } => match event {
@@ -1,30 +1,152 @@
+use crate::decode_queue::Priority;
 use crate::image_loader::{ImageRef, ImageRequest};
+use crate::mipmap::MipmapGenerator;
 use crate::texture;
-use crate::texture::{ImageResolution, SizedImage};
+use crate::texture::{Frame, ImageResolution, Pixels, SizedImage};
+use crate::upload::{PlaneUpload, StagingRing};
 use crate::viewport::Uniforms;
-use anyhow::*;
 use bytemuck::Zeroable;
 use log::debug;
 use logging_timer::time;
 use number_prefix::NumberPrefix;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
 use wgpu::util::DeviceExt;
 
 pub type Orientation = rexiv2::Orientation;
 
+/// Pixels each tile shares with its neighbours, so that filtering across
+/// tile borders samples the same pixels as within a tile.
+const TILE_APRON: u32 = 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Vertex {
+    position: [f32; 3],
+    tex_coords: [f32; 2],
+}
+
+impl Vertex {
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+            ],
+        }
+    }
+}
+
+// 6 indices, forming two triangles of a tile's quad
+pub const INDICES: &[u16] = &[0, 1, 2, 0, 2, 3];
+
+/// Ranges along one image axis: the part a tile shows, and the larger
+/// part its texture holds including the apron. Texture ranges start and end
+/// at multiples of `block`, the chroma subsampling of Y'CbCr images, and may
+/// extend into the padding after `len`.
+fn tile_ranges(len: u32, max_texture_size: u32, block: u32) -> Vec<((u32, u32), (u32, u32))> {
+    let padded = len.next_multiple_of(block);
+    if padded <= max_texture_size {
+        return vec![((0, len), (0, padded))];
+    }
+    // rounding out to whole blocks adds up to `block - 1` on each side
+    let step = (max_texture_size - 2 * (TILE_APRON + block - 1)) as usize;
+    (0..len)
+        .step_by(step)
+        .map(|start| {
+            let end = (start + step as u32).min(len);
+            let texture = (
+                start.saturating_sub(TILE_APRON) / block * block,
+                (end + TILE_APRON).next_multiple_of(block).min(padded),
+            );
+            ((start, end), texture)
+        })
+        .collect()
+}
+
+/// Part of an image in its own textures, one per plane, drawn as its own quad.
+pub struct Tile {
+    pub textures: Vec<wgpu::Texture>,
+    pub bind_group: wgpu::BindGroup,
+    pub vertex_buffer: wgpu::Buffer,
+}
+
+/// Tiles of one animation frame, or of the whole image if it is still.
+pub struct LayerFrame {
+    pub tiles: Vec<Tile>,
+    pub delay: Duration,
+}
+
 pub struct Layer {
     pub image_ref: ImageRef,
     pub resolution: ImageResolution,
     pub orientation: Orientation,
-    pub texture_bind_group: wgpu::BindGroup,
-    pub texture: wgpu::Texture,
+    pub full_size: (u32, u32),
+    /// Size of the decoded image, split into tiles
+    pub size: (u32, u32),
+    pub frames: Vec<LayerFrame>,
+    /// Rasterised from vector graphics, see `SizedImage::vector`
+    pub vector: bool,
+    /// Uploaded as Y'CbCr planes, which the shader converts to RGB
+    pub ycbcr: bool,
     pub uniform_bind_group: wgpu::BindGroup,
     pub uniform_buffer: wgpu::Buffer,
 }
 
 impl Layer {
     fn texture_byte_size(&self) -> usize {
-        self.texture.width() as usize * self.texture.height() as usize * 4
+        let base: usize = self
+            .frames
+            .iter()
+            .flat_map(|f| &f.tiles)
+            .flat_map(|t| &t.textures)
+            .map(|t| {
+                let bytes_per_pixel = t.format().block_size(None).unwrap_or(4);
+                t.width() as usize * t.height() as usize * bytes_per_pixel as usize
+            })
+            .sum();
+        // the mip chain adds a third
+        base * 4 / 3
+    }
+
+    /// Tiles of frame `index`, wrapping around.
+    pub fn tiles(&self, index: usize) -> &[Tile] {
+        &self.frames[index % self.frames.len()].tiles
+    }
+
+    pub fn delays(&self) -> Vec<Duration> {
+        self.frames.iter().map(|f| f.delay).collect()
+    }
+
+    /// Whether the texture holds fewer pixels than the source image.
+    pub fn is_downscaled(&self) -> bool {
+        self.size.0 < self.full_size.0 || self.size.1 < self.full_size.1
+    }
+}
+
+/// A layer whose textures are still being filled, shown once all are complete.
+struct PendingLayer {
+    layer: Layer,
+    /// The decoded pixels, kept until they are copied
+    frames: Arc<Vec<Frame>>,
+    uploads: VecDeque<PlaneUpload>,
+}
+
+impl PendingLayer {
+    fn request(&self) -> ImageRequest {
+        ImageRequest::new(self.layer.image_ref.clone(), self.layer.resolution)
     }
 }
 
@@ -32,52 +154,170 @@ pub struct Layers {
     pub texture_bind_group_layout: wgpu::BindGroupLayout,
     pub uniform_bind_group_layout: wgpu::BindGroupLayout,
     pub layers: HashMap<ImageRef, Vec<Layer>>,
+    pending: Vec<PendingLayer>,
+    staging: StagingRing,
+    /// Bytes of pixels copied per call of `upload`
+    upload_budget: usize,
+    /// Bytes of textures, including those of pending layers
+    gpu_budget: usize,
+    mipmaps: MipmapGenerator,
 }
 
 impl Layers {
     pub fn new(
         texture_bind_group_layout: wgpu::BindGroupLayout,
         uniform_bind_group_layout: wgpu::BindGroupLayout,
+        mipmaps: MipmapGenerator,
+        upload_budget: usize,
+        gpu_budget: usize,
     ) -> Self {
         Self {
             texture_bind_group_layout,
             uniform_bind_group_layout,
             layers: HashMap::new(),
+            pending: Vec::new(),
+            // twice the budget, so that one frame's copies can be staged
+            // while the previous frame's are still in flight
+            staging: StagingRing::new(2 * upload_budget),
+            upload_budget,
+            gpu_budget,
+            mipmaps,
         }
     }
 
+    /// Bind the planes of a tile: RGBA images bind their single texture in
+    /// place of the chroma planes, which the shader then ignores.
     #[time]
-    fn bind_group_for_texture(
+    fn bind_group_for_textures(
         &self,
         device: &wgpu::Device,
-        diffuse_texture: &texture::Texture,
+        textures: &[texture::Texture],
     ) -> wgpu::BindGroup {
+        let view = |plane: usize| &textures[plane.min(textures.len() - 1)].view;
         device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &self.texture_bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                    resource: wgpu::BindingResource::TextureView(view(0)),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                    resource: wgpu::BindingResource::Sampler(&textures[0].sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(view(1)),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(view(2)),
                 },
             ],
             label: Some("diffuse_bind_group"),
         })
     }
 
-    #[time]
-    fn create_layer(
+    /// Create the textures for the `x` × `y` part of `image`, with quad corners
+    /// at the position of the shown part within the whole image. Their
+    /// contents are queued to `uploads`, as tile `tile` of frame `frame`.
+    fn create_tile(
         &self,
         device: &wgpu::Device,
-        image_ref: ImageRef,
-        resolution: ImageResolution,
-        orientation: rexiv2::Orientation,
-        texture: texture::Texture,
-    ) -> Result<Layer> {
-        let texture_bind_group = self.bind_group_for_texture(device, &texture);
+        image: &Pixels,
+        (shown_x, texture_x): ((u32, u32), (u32, u32)),
+        (shown_y, texture_y): ((u32, u32), (u32, u32)),
+        (frame, tile): (usize, usize),
+        uploads: &mut VecDeque<PlaneUpload>,
+    ) -> Tile {
+        let region = (
+            texture_x.0,
+            texture_y.0,
+            texture_x.1 - texture_x.0,
+            texture_y.1 - texture_y.0,
+        );
+        let textures = image
+            .planes()
+            .iter()
+            .enumerate()
+            .map(|(index, plane)| {
+                let (sx, sy) = plane.subsampling;
+                let region = (region.0 / sx, region.1 / sy, region.2 / sx, region.3 / sy);
+                uploads.push_back(PlaneUpload {
+                    frame,
+                    tile,
+                    plane: index,
+                    region,
+                    rows_done: 0,
+                });
+                texture::Texture::empty(device, plane.format, (region.2, region.3), None)
+            })
+            .collect::<Vec<_>>();
+        let bind_group = self.bind_group_for_textures(device, &textures);
+
+        let (width, height) = (image.width() as f32, image.height() as f32);
+        let position = |x: u32, y: u32| [
+            2.0 * x as f32 / width - 1.0,
+            1.0 - 2.0 * y as f32 / height,
+            0.0,
+        ];
+        let tex_coords = |x: u32, y: u32| [
+            (x - texture_x.0) as f32 / region.2 as f32,
+            (y - texture_y.0) as f32 / region.3 as f32,
+        ];
+        let corners = [
+            (shown_x.0, shown_y.0),
+            (shown_x.0, shown_y.1),
+            (shown_x.1, shown_y.1),
+            (shown_x.1, shown_y.0),
+        ];
+        let vertices = corners.map(|(x, y)| Vertex {
+            position: position(x, y),
+            tex_coords: tex_coords(x, y),
+        });
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Tile Vertex Buffer"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        Tile {
+            textures: textures.into_iter().map(|t| t.texture).collect(),
+            bind_group,
+            vertex_buffer,
+        }
+    }
+
+    /// Images larger than the device's texture size limit are split into a grid of tiles.
+    /// The textures are created empty; their contents are queued for `upload`.
+    #[time]
+    fn create_pending_layer(&self, device: &wgpu::Device, sized_image: SizedImage) -> PendingLayer {
+        let max_texture_size = device.limits().max_texture_dimension_2d;
+        let mut uploads = VecDeque::new();
+        let mut frames = Vec::new();
+        for (index, frame) in sized_image.frames.iter().enumerate() {
+            let image = &frame.pixels;
+            let (block_x, block_y) = image.block_size();
+            let mut tiles = Vec::new();
+            for y in tile_ranges(image.height(), max_texture_size, block_y) {
+                for x in tile_ranges(image.width(), max_texture_size, block_x) {
+                    let position = (index, tiles.len());
+                    tiles.push(self.create_tile(device, image, x, y, position, &mut uploads));
+                }
+            }
+            frames.push(LayerFrame {
+                tiles,
+                delay: frame.delay,
+            });
+        }
+        if frames[0].tiles.len() > 1 || frames.len() > 1 {
+            debug!(
+                "{:?}: {} frames of {} tiles",
+                sized_image.image_ref,
+                frames.len(),
+                frames[0].tiles.len()
+            );
+        }
 
         let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Uniform Buffer"),
@@ -85,12 +325,15 @@ impl Layers {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        Ok(Layer {
-            image_ref,
-            resolution,
-            orientation,
-            texture_bind_group: texture_bind_group,
-            texture: texture.texture,
+        let layer = Layer {
+            image_ref: sized_image.image_ref,
+            resolution: sized_image.resolution,
+            orientation: sized_image.orientation,
+            full_size: sized_image.full_size,
+            size: sized_image.frames[0].pixels.dimensions(),
+            frames,
+            vector: sized_image.vector,
+            ycbcr: sized_image.frames[0].pixels.is_ycbcr(),
             uniform_bind_group: device.create_bind_group(&wgpu::BindGroupDescriptor {
                 layout: &self.uniform_bind_group_layout,
                 entries: &[wgpu::BindGroupEntry {
@@ -100,24 +343,12 @@ impl Layers {
                 label: Some("uniform_bind_group"),
             }),
             uniform_buffer,
-        })
-    }
-
-    #[time]
-    pub fn create_layer_from_sized_image(
-        &self,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        sized_image: SizedImage,
-    ) -> Result<Layer> {
-        let texture = texture::Texture::from_rgba(&device, &queue, sized_image.image, None)?;
-        self.create_layer(
-            device,
-            sized_image.image_ref,
-            sized_image.resolution,
-            sized_image.orientation,
-            texture,
-        )
+        };
+        PendingLayer {
+            layer,
+            frames: sized_image.frames,
+            uploads,
+        }
     }
 
     fn get_best_layer<'a, I>(layers: I) -> Option<&'a Layer>
@@ -131,7 +362,9 @@ impl Layers {
         Self::get_best_layer(self.layers.get(iref)?.iter())
     }
 
+    /// Keep only the textures of `reqs`, which the loader planned within the GPU budget.
     pub fn retain(&mut self, reqs: &[ImageRequest]) {
+        self.pending.retain(|p| reqs.contains(&p.request()));
         self.layers.retain(|iref, layers| {
             layers.retain(|l| {
                 reqs.iter()
@@ -139,17 +372,26 @@ impl Layers {
             });
             !layers.is_empty()
         });
+        self.dump_layer_info();
+    }
+
+    /// Drop the textures of all pages of the file of `iref`, e.g. after it changed.
+    pub fn remove(&mut self, iref: &ImageRef) {
+        self.pending.retain(|p| p.layer.image_ref.path != iref.path);
+        self.layers.retain(|key, _| key.path != iref.path);
+    }
+
+    /// Bytes of all textures, those still being filled included.
+    fn texture_byte_size(&self) -> usize {
+        let shown = self.layers.values().flatten();
+        let pending = self.pending.iter().map(|p| &p.layer);
+        shown.chain(pending).map(|l| l.texture_byte_size()).sum()
     }
 
     fn dump_layer_info(&self) {
-        let total_texture_bytes = self
-            .layers
-            .values()
-            .flatten()
-            .map(|l| l.texture_byte_size())
-            .reduce(|a, b| a + b);
-
-        debug!("Layers: {}", self.layers.len());
+        let total_texture_bytes = Some(self.texture_byte_size()).filter(|&b| b > 0);
+
+        debug!("Layers: {}, pending: {}", self.layers.len(), self.pending.len());
         debug!(
             "Texture Bytes: {}",
             total_texture_bytes
@@ -176,14 +418,124 @@ impl Layers {
         self.dump_layer_info();
     }
 
-    pub fn add_layer_from_sized_image(
+    /// Queue a decoded image for upload; it replaces a layer of the same
+    /// resolution once `upload` has copied all of it. The loader plans one
+    /// texture per request, so if keeping both would exceed the GPU budget,
+    /// the replaced layer is dropped right away.
+    pub fn add_layer_from_sized_image(&mut self, device: &wgpu::Device, sized_image: SizedImage) {
+        let pending = self.create_pending_layer(device, sized_image);
+        let request = pending.request();
+        self.pending.retain(|p| p.request() != request);
+        self.pending.push(pending);
+        if self.texture_byte_size() > self.gpu_budget {
+            if let Some(layers) = self.layers.get_mut(&request.reference) {
+                layers.retain(|l| l.resolution != request.resolution);
+                if layers.is_empty() {
+                    self.layers.remove(&request.reference);
+                }
+            }
+        }
+        self.dump_layer_info();
+    }
+
+    /// Whether layers are waiting for `upload`.
+    pub fn is_uploading(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Copy up to the upload budget of queued pixels to the GPU, in order of
+    /// `priority`, and add the layers that are complete. Returns whether any were.
+    #[time]
+    pub fn upload(
         &mut self,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        sized_image: SizedImage,
-    ) -> Result<()> {
-        let layer = self.create_layer_from_sized_image(device, queue, sized_image)?;
-        self.add_layer(layer);
-        Ok(())
+        priority: impl Fn(&ImageRequest) -> Priority,
+    ) -> bool {
+        if self.pending.is_empty() {
+            return false;
+        }
+        self.staging.recall(device);
+        self.pending.sort_by_cached_key(|p| priority(&p.request()));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Upload Encoder"),
+        });
+        let mut used = Vec::new();
+        let mut uploaded = 0;
+        'pending: for pending in &mut self.pending {
+            while let Some(upload) = pending.uploads.front_mut() {
+                // at least one chunk per frame, however small the budget
+                if uploaded >= self.upload_budget && !used.is_empty() {
+                    break 'pending;
+                }
+                let planes = pending.frames[upload.frame].pixels.planes();
+                let plane = &planes[upload.plane];
+                let texture =
+                    &pending.layer.frames[upload.frame].tiles[upload.tile].textures[upload.plane];
+                let Some((bytes, buffer)) =
+                    self.staging.copy_rows(device, &mut encoder, plane, upload, texture)
+                else {
+                    // all staging buffers are in flight
+                    break 'pending;
+                };
+                uploaded += bytes;
+                used.push(buffer);
+                if upload.is_done() {
+                    pending.uploads.pop_front();
+                }
+            }
+        }
+        queue.submit(Some(encoder.finish()));
+        self.staging.remap(used);
+
+        let (done, pending) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|p| p.uploads.is_empty());
+        self.pending = pending;
+        let added = !done.is_empty();
+        for pending in done {
+            let tiles = pending.layer.frames.iter().flat_map(|f| &f.tiles);
+            for texture in tiles.flat_map(|t| &t.textures) {
+                self.mipmaps.generate(device, queue, texture);
+            }
+            self.add_layer(pending.layer);
+        }
+        if uploaded > 0 {
+            debug!("uploaded {} bytes, {} layers pending", uploaded, self.pending.len());
+        }
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_tile_is_padded_to_whole_blocks() {
+        assert_eq!(tile_ranges(101, 4096, 1), vec![((0, 101), (0, 101))]);
+        assert_eq!(tile_ranges(101, 4096, 2), vec![((0, 101), (0, 102))]);
+    }
+
+    #[test]
+    fn tiles_cover_the_image_with_aligned_aprons() {
+        let len = 10001;
+        for block in [1, 2] {
+            let ranges = tile_ranges(len, 4096, block);
+            assert_eq!(ranges.len(), 3);
+            assert_eq!(ranges[0].0 .0, 0);
+            assert_eq!(ranges[ranges.len() - 1].0 .1, len);
+            for pair in ranges.windows(2) {
+                assert_eq!(pair[0].0 .1, pair[1].0 .0);
+            }
+            for &((start, end), (texture_start, texture_end)) in &ranges {
+                assert_eq!(texture_start % block, 0);
+                assert_eq!(texture_end % block, 0);
+                assert!(texture_end - texture_start <= 4096);
+                assert!(texture_start <= start.saturating_sub(TILE_APRON));
+                assert!(texture_end >= (end + TILE_APRON).min(len));
+                assert!(texture_end <= len.next_multiple_of(block));
+            }
+        }
     }
 }
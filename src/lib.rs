@@ -1,11 +1,29 @@
+mod animation;
+mod archive;
+mod buffer_pool;
+mod color;
 pub mod config;
+mod decode_queue;
+pub mod format;
 pub mod image_loader;
 mod input_events;
 mod layers;
+mod load_error;
 pub mod logger;
+mod mipmap;
+mod navigation;
 mod overlay;
+mod pages;
+mod raw;
+pub mod scan;
+pub mod sort;
 pub mod viewer;
 mod storage;
+mod svg;
 pub mod texture;
+pub mod thumbnail_cache;
+mod upload;
 mod viewport;
+pub mod watcher;
+mod wake;
 mod fps_meter;
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::io;
+
+/// Why an image could not be loaded. Failed images are drawn as a placeholder
+/// showing the reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file vanished after it was listed
+    NotFound,
+    /// The file exists but cannot be read, e.g. for lack of permissions
+    Unreadable(String),
+    /// Not a format that can be decoded
+    Unsupported(String),
+    /// The data is damaged beyond a partial decode
+    Corrupt(String),
+    /// Decoding stopped because the image is no longer needed
+    Cancelled,
+}
+
+impl LoadError {
+    /// Classify an error from the decoding functions, which return `anyhow` errors
+    /// wrapping a `LoadError`, an I/O error or an `image` crate error where they can.
+    /// Anything else is taken to mean that the data is corrupt.
+    pub fn classify(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<LoadError>() {
+                return e.clone();
+            }
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                return Self::from_io(e);
+            }
+            if let Some(e) = cause.downcast_ref::<image::ImageError>() {
+                return match e {
+                    image::ImageError::Unsupported(_) => Self::Unsupported(e.to_string()),
+                    image::ImageError::IoError(e) => Self::from_io(e),
+                    _ => Self::Corrupt(e.to_string()),
+                };
+            }
+        }
+        Self::Corrupt(error.to_string())
+    }
+
+    fn from_io(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            // a file cut short, e.g. while it is still being copied
+            io::ErrorKind::UnexpectedEof => Self::Corrupt(e.to_string()),
+            _ => Self::Unreadable(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        Self::from_io(&e)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "file not found"),
+            Self::Unreadable(reason) => write!(f, "cannot read file: {}", reason),
+            Self::Unsupported(reason) => write!(f, "unsupported format: {}", reason),
+            Self::Corrupt(reason) => write!(f, "corrupt image: {}", reason),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    fn classify_io(kind: io::ErrorKind) -> LoadError {
+        let error = Err::<(), _>(io::Error::new(kind, "test")).context("reading a.jpg");
+        LoadError::classify(&error.unwrap_err())
+    }
+
+    #[test]
+    fn io_errors_by_kind() {
+        assert_eq!(classify_io(io::ErrorKind::NotFound), LoadError::NotFound);
+        assert_eq!(
+            classify_io(io::ErrorKind::PermissionDenied),
+            LoadError::Unreadable("test".into())
+        );
+        assert_eq!(classify_io(io::ErrorKind::UnexpectedEof), LoadError::Corrupt("test".into()));
+    }
+
+    #[test]
+    fn wrapped_and_unknown_errors() {
+        let cancelled = anyhow!(LoadError::Cancelled).context("decoding a.jpg");
+        assert_eq!(LoadError::classify(&cancelled), LoadError::Cancelled);
+        let unknown = image::load_from_memory(b"not an image").unwrap_err();
+        assert!(matches!(LoadError::classify(&unknown.into()), LoadError::Unsupported(_)));
+        let damaged = anyhow!("bad Huffman table");
+        assert_eq!(LoadError::classify(&damaged), LoadError::Corrupt("bad Huffman table".into()));
+    }
+}
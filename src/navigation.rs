@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Steps older than this do not count towards the browsing speed.
+const SPEED_WINDOW: Duration = Duration::from_secs(2);
+
+/// Steps per second from which browsing counts as fast.
+const FAST_STEPS_PER_SEC: f64 = 3.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Tracks direction and pace of next/prev navigation.
+pub struct Navigation {
+    direction: Option<Direction>,
+    steps: VecDeque<Instant>,
+}
+
+impl Navigation {
+    pub fn new() -> Self {
+        Self {
+            direction: None,
+            steps: VecDeque::new(),
+        }
+    }
+
+    pub fn step(&mut self, direction: Direction) {
+        let now = Instant::now();
+        if self.direction != Some(direction) {
+            self.steps.clear();
+        }
+        self.direction = Some(direction);
+        self.steps.push_back(now);
+        while let Some(step) = self.steps.front() {
+            if now.duration_since(*step) <= SPEED_WINDOW {
+                break;
+            }
+            self.steps.pop_front();
+        }
+    }
+
+    pub fn direction(&self) -> Option<Direction> {
+        self.direction
+    }
+
+    /// Average time between the recent steps, `None` after a pause longer
+    /// than `max_interval` or with too few steps to tell.
+    fn interval(&self, max_interval: Duration) -> Option<Duration> {
+        let last = *self.steps.back()?;
+        if last.elapsed() > max_interval.min(SPEED_WINDOW) || self.steps.len() < 3 {
+            return None;
+        }
+        let first = *self.steps.front()?;
+        Some(last.duration_since(first) / (self.steps.len() - 1) as u32)
+    }
+
+    /// When a pause longer than `max_interval` will have begun, if no step follows.
+    pub fn pause_deadline(&self, max_interval: Duration) -> Option<Instant> {
+        let last = *self.steps.back()?;
+        Some(last + max_interval.min(SPEED_WINDOW) + Duration::from_millis(1))
+    }
+
+    /// Browsing steadily at several images per second.
+    pub fn is_fast(&self) -> bool {
+        let fast = Duration::from_secs_f64(1.0 / FAST_STEPS_PER_SEC);
+        self.interval(fast).is_some_and(|interval| interval <= fast)
+    }
+
+    /// Moving on faster than an image takes to decode.
+    pub fn is_faster_than(&self, decode_time: Duration) -> bool {
+        self.interval(decode_time)
+            .is_some_and(|interval| interval < decode_time)
+    }
+
+    /// How far the preload window reaches (ahead, behind), in percent of a
+    /// symmetric window. Browsing one way shifts it forward, e.g. 6 ahead and
+    /// 2 behind instead of 4 on each side, and further ahead when fast.
+    pub fn reach(&self) -> (usize, usize) {
+        match (self.direction, self.is_fast()) {
+            (None, _) => (100, 100),
+            (Some(_), false) => (150, 50),
+            (Some(_), true) => (300, 50),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reach_shifts_in_the_direction_of_browsing() {
+        let mut navigation = Navigation::new();
+        assert_eq!(navigation.reach(), (100, 100));
+        navigation.step(Direction::Backward);
+        assert_eq!(navigation.reach(), (150, 50));
+        // several steps in quick succession
+        navigation.step(Direction::Backward);
+        navigation.step(Direction::Backward);
+        assert_eq!(navigation.reach(), (300, 50));
+        // turning around starts slow again
+        navigation.step(Direction::Forward);
+        assert_eq!(navigation.reach(), (150, 50));
+    }
+}
@@ -96,6 +96,10 @@ impl OverlayElement {
             let total_height = self.buffer.lines.len() as f32 * self.buffer.metrics().line_height;
             self.offset.1 = self.size.height as f32 - total_height - 10.0;
         }
+        if self.position.1 == VerticalPosition::Center {
+            let total_height = self.buffer.lines.len() as f32 * self.buffer.metrics().line_height;
+            self.offset.1 = ((size.height as f32 - total_height) / 2.0).max(0.0);
+        }
 
         self.text = text;
         self.size = size;
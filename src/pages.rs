@@ -0,0 +1,276 @@
+use crate::format::{read_jpeg_segments, FileFormat};
+use anyhow::*;
+use image::{DynamicImage, ImageBuffer};
+use log::debug;
+use std::io::{Cursor, Read, Seek};
+use std::path::Path;
+use tiff::decoder::ifd::Value;
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+use tiff::tags::Tag;
+use tiff::ColorType;
+
+/// Identifier at the start of the JPEG APP2 segment with the MPO index.
+const MPF_MARKER: &[u8] = b"MPF\0";
+
+const MP_NUMBER_OF_IMAGES: u16 = 0xB001;
+const MP_ENTRY: u16 = 0xB002;
+const MP_ENTRY_LEN: usize = 16;
+
+/// TIFF tag of an embedded ICC profile, which each page may carry.
+const TIFF_ICC_PROFILE: u16 = 34675;
+
+/// A decoded TIFF page, with the tags that describe it.
+pub struct TiffPage {
+    pub image: DynamicImage,
+    pub icc_profile: Option<Vec<u8>>,
+    /// Orientation as numbered by TIFF and EXIF, 1 to 8
+    pub orientation: Option<u16>,
+}
+
+/// Number of pages of a multi-page TIFF or the images of an MPO file, 1 for other files.
+/// `path` names the file or archive entry in messages.
+pub fn page_count(path: &Path, bytes: &[u8], format: FileFormat) -> usize {
+    let count = match format {
+        FileFormat::Tiff => tiff_page_count(Cursor::new(bytes)),
+        FileFormat::Jpeg => mpo_images(bytes).map(|images| images.len()),
+        _ => return 1,
+    };
+    count
+        .map_err(|e| debug!("{}: failed to count pages: {}", path.display(), e))
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Only the image directories are read, not the pixel data.
+fn tiff_page_count(reader: impl Read + Seek) -> Result<usize> {
+    let mut decoder = Decoder::new(reader)?;
+    let mut count = 1;
+    while decoder.more_images() {
+        decoder.next_image()?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Bytes of a tag stored as UNDEFINED, as profiles should be, or as BYTE.
+fn tag_bytes(value: Value) -> Option<Vec<u8>> {
+    let byte = |value| match value {
+        Value::Byte(byte) => Some(byte),
+        Value::Unsigned(value) => u8::try_from(value).ok(),
+        _ => None,
+    };
+    match value {
+        Value::List(values) => values.into_iter().map(byte).collect(),
+        value => byte(value).map(|byte| vec![byte]),
+    }
+}
+
+/// Decode page `page` (0-based) of a TIFF file.
+pub fn decode_tiff_page(bytes: &[u8], page: usize) -> Result<TiffPage> {
+    let mut decoder = Decoder::new(Cursor::new(bytes))?.with_limits(Limits::unlimited());
+    decoder.seek_to_image(page)?;
+    let icc_profile = decoder
+        .find_tag(Tag::Unknown(TIFF_ICC_PROFILE))?
+        .and_then(tag_bytes);
+    let orientation = decoder.find_tag_unsigned(Tag::Orientation)?;
+    let (width, height) = decoder.dimensions()?;
+    let color_type = decoder.colortype()?;
+    let buffer_error = || anyhow!("TIFF page {}: buffer size mismatch", page);
+    let image = match (color_type, decoder.read_image()?) {
+        (ColorType::Gray(8), DecodingResult::U8(data)) => DynamicImage::ImageLuma8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::GrayA(8), DecodingResult::U8(data)) => DynamicImage::ImageLumaA8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::RGB(8), DecodingResult::U8(data)) => DynamicImage::ImageRgb8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::RGBA(8), DecodingResult::U8(data)) => DynamicImage::ImageRgba8(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::Gray(16), DecodingResult::U16(data)) => DynamicImage::ImageLuma16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::GrayA(16), DecodingResult::U16(data)) => DynamicImage::ImageLumaA16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::RGB(16), DecodingResult::U16(data)) => DynamicImage::ImageRgb16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::RGBA(16), DecodingResult::U16(data)) => DynamicImage::ImageRgba16(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::RGB(32), DecodingResult::F32(data)) => DynamicImage::ImageRgb32F(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (ColorType::RGBA(32), DecodingResult::F32(data)) => DynamicImage::ImageRgba32F(
+            ImageBuffer::from_raw(width, height, data).ok_or_else(buffer_error)?,
+        ),
+        (color_type, _) => {
+            return Err(anyhow!(
+                "TIFF page {}: unsupported colour type {:?}",
+                page,
+                color_type
+            ))
+        }
+    };
+    Ok(TiffPage {
+        image,
+        icc_profile,
+        orientation,
+    })
+}
+
+/// The JPEG stream of image `page` (0-based) inside an MPO file.
+pub fn mpo_page(bytes: &[u8], page: usize) -> Result<&[u8]> {
+    let images = mpo_images(Cursor::new(bytes))?;
+    let (offset, size) = *images
+        .get(page)
+        .ok_or(anyhow!("MPO has no image {}", page))?;
+    let end = offset.saturating_add(size).min(bytes.len() as u64);
+    bytes
+        .get(offset as usize..end as usize)
+        .ok_or(anyhow!("MPO image {} is outside the file", page))
+}
+
+/// Offsets and sizes of the images listed in the MP index of an MPO file,
+/// or a single entry for plain JPEGs without one.
+fn mpo_images(reader: impl Read) -> Result<Vec<(u64, u64)>> {
+    let mut index = None;
+    read_jpeg_segments(reader, |marker, offset, payload| {
+        if marker == 0xE2 && index.is_none() && payload.starts_with(MPF_MARKER) {
+            // offsets in the index are relative to its TIFF header, after the identifier
+            let base = offset + MPF_MARKER.len() as u64;
+            index = Some(parse_mp_index(&payload[MPF_MARKER.len()..], base));
+        }
+    })?;
+    match index {
+        Some(images) => images,
+        None => Ok(vec![(0, u64::MAX)]),
+    }
+}
+
+/// Parse the MP index IFD, which is laid out like a TIFF directory.
+fn parse_mp_index(data: &[u8], base: u64) -> Result<Vec<(u64, u64)>> {
+    let little_endian = match data.get(..4) {
+        Some([b'I', b'I', 0x2A, 0x00]) => true,
+        Some([b'M', b'M', 0x00, 0x2A]) => false,
+        _ => return Err(anyhow!("invalid MP index header")),
+    };
+    let read_u16 = |at: usize| -> Result<u16> {
+        let b: [u8; 2] = data
+            .get(at..at + 2)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(anyhow!("MP index truncated"))?;
+        Ok(if little_endian { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) })
+    };
+    let read_u32 = |at: usize| -> Result<u32> {
+        let b: [u8; 4] = data
+            .get(at..at + 4)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(anyhow!("MP index truncated"))?;
+        Ok(if little_endian { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+    };
+    let ifd = read_u32(4)? as usize;
+    let mut count = None;
+    let mut entries = None;
+    for i in 0..read_u16(ifd)? as usize {
+        let entry = ifd + 2 + i * 12;
+        match read_u16(entry)? {
+            MP_NUMBER_OF_IMAGES => count = Some(read_u32(entry + 8)? as usize),
+            MP_ENTRY => entries = Some(read_u32(entry + 8)? as usize),
+            _ => {}
+        }
+    }
+    let (count, entries) = count
+        .zip(entries)
+        .ok_or(anyhow!("MP index without image entries"))?;
+    (0..count)
+        .map(|i| {
+            let entry = entries + i * MP_ENTRY_LEN;
+            let size = read_u32(entry + 4)? as u64;
+            // the first image starts at the beginning of the file, its offset is 0
+            let offset = match read_u32(entry + 8)? {
+                0 => 0,
+                offset => base + offset as u64,
+            };
+            Ok((offset, size))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An MP index with two images, in the byte order of `header`.
+    fn mp_index(
+        header: [u8; 4],
+        u16_bytes: fn(u16) -> [u8; 2],
+        u32_bytes: fn(u32) -> [u8; 4],
+    ) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.extend(u32_bytes(8));
+        data.extend(u16_bytes(2));
+        // tag, type, count, value
+        data.extend(u16_bytes(MP_NUMBER_OF_IMAGES));
+        data.extend(u16_bytes(4));
+        data.extend(u32_bytes(1));
+        data.extend(u32_bytes(2));
+        data.extend(u16_bytes(MP_ENTRY));
+        data.extend(u16_bytes(7));
+        data.extend(u32_bytes(2 * MP_ENTRY_LEN as u32));
+        data.extend(u32_bytes(38));
+        // no next IFD
+        data.extend(u32_bytes(0));
+        // attributes, size, offset, dependent images
+        for (size, offset) in [(1000, 0), (500, 200)] {
+            data.extend(u32_bytes(0));
+            data.extend(u32_bytes(size));
+            data.extend(u32_bytes(offset));
+            data.extend(u32_bytes(0));
+        }
+        data
+    }
+
+    #[test]
+    fn parse_little_endian_index() {
+        let data = mp_index(*b"II*\0", u16::to_le_bytes, u32::to_le_bytes);
+        assert_eq!(parse_mp_index(&data, 10).unwrap(), [(0, 1000), (210, 500)]);
+    }
+
+    #[test]
+    fn parse_big_endian_index() {
+        let data = mp_index([b'M', b'M', 0, 0x2A], u16::to_be_bytes, u32::to_be_bytes);
+        assert_eq!(parse_mp_index(&data, 10).unwrap(), [(0, 1000), (210, 500)]);
+    }
+
+    #[test]
+    fn reject_damaged_index() {
+        assert!(parse_mp_index(b"XX*\0", 0).is_err());
+        let data = mp_index(*b"II*\0", u16::to_le_bytes, u32::to_le_bytes);
+        assert!(parse_mp_index(&data[..40], 0).is_err());
+    }
+
+    #[test]
+    fn tiff_pages_keep_their_tags() {
+        use tiff::encoder::{colortype, TiffEncoder};
+        let mut bytes = Vec::new();
+        let mut encoder = TiffEncoder::new(Cursor::new(&mut bytes)).unwrap();
+        encoder.write_image::<colortype::Gray8>(2, 1, &[0, 255]).unwrap();
+        let mut image = encoder.new_image::<colortype::RGB8>(1, 2).unwrap();
+        image.encoder().write_tag(Tag::Orientation, 6u16).unwrap();
+        image.encoder().write_tag(Tag::Unknown(TIFF_ICC_PROFILE), &[1u8, 2, 3][..]).unwrap();
+        image.write_data(&[255, 0, 0, 0, 0, 255]).unwrap();
+
+        assert_eq!(page_count(Path::new("a.tif"), &bytes, FileFormat::Tiff), 2);
+        let first = decode_tiff_page(&bytes, 0).unwrap();
+        assert_eq!(first.orientation, None);
+        assert_eq!(first.icc_profile, None);
+        let second = decode_tiff_page(&bytes, 1).unwrap();
+        assert_eq!(second.orientation, Some(6));
+        assert_eq!(second.icc_profile, Some(vec![1, 2, 3]));
+        assert_eq!(second.image.to_rgb8().into_raw(), [255, 0, 0, 0, 0, 255]);
+    }
+}
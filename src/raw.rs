@@ -0,0 +1,102 @@
+use anyhow::*;
+use image::DynamicImage;
+use log::debug;
+use rexiv2::Metadata;
+use std::path::{Path, PathBuf};
+
+/// File extensions of the supported camera RAW formats, lower case.
+pub const RAW_EXTENSIONS: [&str; 6] = ["cr2", "cr3", "nef", "arw", "dng", "raf"];
+
+const JPEG_EXTENSIONS: [&str; 2] = ["jpg", "jpeg"];
+
+/// Whether RAW files are demosaiced at native resolution (`raw-decode` feature).
+/// Otherwise the largest embedded preview is their native resolution.
+pub const FULL_DECODE: bool = cfg!(feature = "raw-decode");
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| extensions.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+pub fn is_raw_path(path: &Path) -> bool {
+    has_extension(path, &RAW_EXTENSIONS)
+}
+
+/// `path` with each of `extensions`, in lower and upper case.
+fn with_extensions(path: &Path, extensions: &[&str]) -> Vec<PathBuf> {
+    extensions
+        .iter()
+        .flat_map(|ext| [ext.to_string(), ext.to_ascii_uppercase()])
+        .map(|ext| path.with_extension(ext))
+        .collect()
+}
+
+/// The JPEG the camera wrote next to a RAW file, e.g. `IMG_1234.JPG` for `IMG_1234.CR2`.
+/// A RAW+JPEG pair is one entry in the sequence, shown through the JPEG, and with
+/// `FULL_DECODE` demosaiced from the RAW file at native resolution.
+pub fn jpeg_partner(raw_path: &Path) -> Option<PathBuf> {
+    with_extensions(raw_path, &JPEG_EXTENSIONS)
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+/// Paths a RAW partner of a JPEG could have.
+pub fn raw_partners(jpeg_path: &Path) -> Vec<PathBuf> {
+    if !has_extension(jpeg_path, &JPEG_EXTENSIONS) {
+        return Vec::new();
+    }
+    with_extensions(jpeg_path, &RAW_EXTENSIONS)
+}
+
+/// The RAW file the camera wrote next to a JPEG, if there is one.
+pub fn raw_partner(jpeg_path: &Path) -> Option<PathBuf> {
+    raw_partners(jpeg_path)
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+/// Bytes of the largest preview image embedded in a RAW file, usually a JPEG.
+pub fn largest_preview(metadata: &Metadata) -> Option<Vec<u8>> {
+    let previews = metadata.get_preview_images()?;
+    let preview = previews
+        .iter()
+        .max_by_key(|p| p.get_width() as u64 * p.get_height() as u64)?;
+    debug!(
+        "RAW preview {}x{}, {} bytes",
+        preview.get_width(),
+        preview.get_height(),
+        preview.get_size()
+    );
+    preview.get_data().ok()
+}
+
+/// Demosaic a RAW file at full resolution. The result is not rotated,
+/// orientation is applied when drawing as for other formats.
+#[cfg(feature = "raw-decode")]
+pub fn decode(path: &Path) -> Result<DynamicImage> {
+    let mut pipeline = imagepipe::Pipeline::new_from_file(path)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    pipeline.ops.transform = imagepipe::transform::OpTransform {
+        rotation: imagepipe::Rotation::Normal,
+        fliph: false,
+        flipv: false,
+    };
+    let decoded = pipeline
+        .output_8bit(None)
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    let image =
+        image::RgbImage::from_vec(decoded.width as u32, decoded.height as u32, decoded.data)
+            .ok_or(anyhow!("RAW buffer size mismatch"))?;
+    Ok(DynamicImage::ImageRgba8(
+        DynamicImage::ImageRgb8(image).to_rgba8(),
+    ))
+}
+
+#[cfg(not(feature = "raw-decode"))]
+pub fn decode(path: &Path) -> Result<DynamicImage> {
+    Err(anyhow!(
+        "{}: built without the raw-decode feature",
+        path.display()
+    ))
+}
@@ -0,0 +1,156 @@
+use crate::archive;
+use crate::config::Config;
+use crate::format::FileFormat;
+use crate::raw;
+use crate::sort::natural_cmp;
+use anyhow::*;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::warn;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Parse a newline or NUL separated list of paths, as printed by `find [-print0]`.
+pub fn read_path_list(mut reader: impl Read, null_separated: bool) -> Result<Vec<PathBuf>> {
+    use std::os::unix::ffi::OsStrExt;
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    let separator = if null_separated { b'\0' } else { b'\n' };
+    Ok(bytes
+        .split(|b| *b == separator)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| PathBuf::from(std::ffi::OsStr::from_bytes(entry)))
+        .collect())
+}
+
+/// Subdirectories and archives directly inside `dir` that can be opened in
+/// its place, in natural order. Hidden entries are skipped.
+pub fn list_subdirectories(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| anyhow!("{}: {}", dir.display(), e))? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if !hidden && (path.is_dir() || archive::is_archive_path(&path)) {
+            paths.push(path);
+        }
+    }
+    paths.sort_by(|a, b| natural_cmp(a, b));
+    Ok(paths)
+}
+
+/// Which files a directory scan picks up.
+#[derive(Clone)]
+pub struct ScanOptions {
+    pub max_depth: usize,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| anyhow!("invalid glob {}: {}", pattern, e))?);
+    }
+    Ok(builder.build()?)
+}
+
+impl ScanOptions {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let max_depth = match (config.recursive, config.max_depth) {
+            (_, Some(depth)) => depth,
+            (true, None) => usize::MAX,
+            (false, None) => 1,
+        };
+        let include = if config.include.is_empty() {
+            None
+        } else {
+            Some(build_glob_set(&config.include)?)
+        };
+        Ok(Self {
+            max_depth,
+            include,
+            exclude: build_glob_set(&config.exclude)?,
+        })
+    }
+
+    /// Whether `path`, relative to the scanned directory, passes the glob filters.
+    pub fn matches(&self, relative: &Path) -> bool {
+        if self.exclude.is_match(relative) {
+            return false;
+        }
+        match &self.include {
+            Some(include) => include.is_match(relative),
+            None => true,
+        }
+    }
+
+    /// Whether `path` below `root` should be shown.
+    /// RAW files with a JPEG next to them are shown through the JPEG.
+    pub fn accepts(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        relative.components().count() <= self.max_depth
+            && self.matches(relative)
+            && path.is_file()
+            && FileFormat::from_path(path).is_some()
+            && !(raw::is_raw_path(path) && raw::jpeg_partner(path).is_some())
+    }
+
+    /// List all images below `dir`, sorted by path.
+    /// Symlinks are followed; loops are detected and skipped.
+    pub fn scan_dir(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        self.scan_subdir(dir, dir)
+    }
+
+    /// List all images below `dir`, which is inside the scanned directory `root`.
+    /// Depth limits and globs are applied relative to `root`.
+    pub fn scan_subdir(&self, root: &Path, dir: &Path) -> Result<Vec<PathBuf>> {
+        let depth = dir.strip_prefix(root).map(|d| d.components().count()).unwrap_or(0);
+        if depth >= self.max_depth {
+            return Ok(Vec::new());
+        }
+        let mut paths = Vec::new();
+        let walker = WalkDir::new(dir)
+            .min_depth(1)
+            .max_depth(self.max_depth - depth)
+            .follow_links(true)
+            .into_iter()
+            .filter_entry(|entry| {
+                let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                !self.exclude.is_match(relative)
+            });
+        for entry in walker {
+            let entry = match entry {
+                Result::Ok(entry) => entry,
+                Err(e) if e.depth() == 0 => {
+                    return Err(anyhow!("{}: {}", dir.display(), e));
+                }
+                Err(e) => {
+                    warn!("skipping: {}", e);
+                    continue;
+                }
+            };
+            if entry.file_type().is_file() && self.accepts(root, entry.path()) {
+                paths.push(entry.into_path());
+            }
+        }
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_lists_skip_empty_entries() {
+        let lines = read_path_list(&b"a.jpg\n\ndir/b c.png\n"[..], false).unwrap();
+        assert_eq!(lines, ["a.jpg", "dir/b c.png"].map(PathBuf::from));
+        // file names may contain newlines, `-print0` output keeps them apart
+        let nul = read_path_list(&b"a\nb.jpg\0c.jpg\0"[..], true).unwrap();
+        assert_eq!(nul, ["a\nb.jpg", "c.jpg"].map(PathBuf::from));
+    }
+}
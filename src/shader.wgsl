@@ -4,13 +4,32 @@ struct Uniforms {
     image_size: vec2<f32>,
     cursor: vec2<f32>,
     alpha: f32,
+    // see FilterMode in texture.rs
+    filter_mode: u32,
+    // stops, applied to linear light
+    exposure: f32,
+    // see ToneMapping in texture.rs
+    tone_mapping: u32,
+    // 1 if the textures are the Y'CbCr planes of a JPEG, see Pixels in texture.rs
+    ycbcr: u32,
 };
 
 @group(1) @binding(0)
 var<uniform> u: Uniforms;
 
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+// chroma planes, possibly subsampled; RGBA images bind t_diffuse again
+@group(0) @binding(2)
+var t_cb: texture_2d<f32>;
+@group(0) @binding(3)
+var t_cr: texture_2d<f32>;
+
+// size of the texture being sampled, which is a tile for large images
 fn getImageSize() -> vec2<f32> {
-    return u.image_size;
+    return vec2<f32>(textureDimensions(t_diffuse));
 }
 
 struct VertexInput {
@@ -35,13 +54,8 @@ fn vs_main(
 
 // Fragment shader
 
-@group(0) @binding(0)
-var t_diffuse: texture_2d<f32>;
-@group(0) @binding(1)
-var s_diffuse: sampler;
 
-
-fn cubicHermite(A: vec3<f32>, B: vec3<f32>, C: vec3<f32>, D: vec3<f32>, t: f32) -> vec3<f32> {
+fn cubicHermite(A: vec4<f32>, B: vec4<f32>, C: vec4<f32>, D: vec4<f32>, t: f32) -> vec4<f32> {
     let t2 = t * t;
     let t3 = t * t * t;
     let a = -A / 2.0 + 3.0 * B / 2.0 - 3.0 * C / 2.0 + D / 2.0;
@@ -51,41 +65,174 @@ fn cubicHermite(A: vec3<f32>, B: vec3<f32>, C: vec3<f32>, D: vec3<f32>, t: f32)
     return a * t3 + b * t2 + c * t + d;
 }
 
-fn scaleBicubicHermite(P: vec2<f32>) -> vec4<f32> {
-    let imageSize = getImageSize();
-    let onePixel = 1.0 / imageSize;
-    let twoPixels = onePixel * 2.0;
+fn srgbToLinear(c: vec3<f32>) -> vec3<f32> {
+    let low = c / 12.92;
+    let high = pow((c + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, c <= vec3<f32>(0.04045));
+}
 
-    let P1 = P * imageSize + 0.5;
+// full range BT.601, as used by JPEG; the result is sRGB encoded
+fn ycbcrToLinear(y: f32, cb: f32, cr: f32) -> vec3<f32> {
+    let rgb = vec3<f32>(
+        y + 1.402 * (cr - 0.5),
+        y - 0.344136 * (cb - 0.5) - 0.714136 * (cr - 0.5),
+        y + 1.772 * (cb - 0.5),
+    );
+    return srgbToLinear(clamp(rgb, vec3<f32>(0.0), vec3<f32>(1.0)));
+}
+
+// premultiplied, so that transparent texels do not bleed their colour into filtering
+fn premultiply(c: vec4<f32>) -> vec4<f32> {
+    return vec4<f32>(c.xyz * c.w, c.w);
+}
+
+// premultiplied texel of mip `level`, clamped to the texture edge
+fn texel(p: vec2<i32>, level: i32) -> vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_diffuse, level));
+    let q = clamp(p, vec2<i32>(0), size - 1);
+    let value = textureLoad(t_diffuse, q, level);
+    if (u.ycbcr == 0u) {
+        return premultiply(value);
+    }
+    // chroma planes cover the same area with fewer texels, interpolate between them
+    let uv = (vec2<f32>(q) + 0.5) / vec2<f32>(size);
+    let cb = textureSampleLevel(t_cb, s_diffuse, uv, f32(level)).x;
+    let cr = textureSampleLevel(t_cr, s_diffuse, uv, f32(level)).x;
+    return vec4<f32>(ycbcrToLinear(value.x, cb, cr), 1.0);
+}
+
+// interpolates converted texels, so that Y'CbCr planes are filtered in linear light
+// like sRGB textures, whose hardware filtering converts before interpolating
+fn scaleBilinear(P: vec2<f32>, level: i32) -> vec4<f32> {
+    let imageSize = vec2<f32>(textureDimensions(t_diffuse, level));
+    let P1 = P * imageSize - 0.5;
     let frac = fract(P1);
-    let pixel = floor(P1) / imageSize - onePixel / 2.0;
+    let pixel = vec2<i32>(floor(P1));
+
+    let top = mix(texel(pixel, level), texel(pixel + vec2<i32>(1, 0), level), frac.x);
+    let bottom = mix(
+        texel(pixel + vec2<i32>(0, 1), level),
+        texel(pixel + vec2<i32>(1, 1), level),
+        frac.x
+    );
+    return mix(top, bottom, frac.y);
+}
 
-    var C: array<vec3<f32>, 16>;
-    var idx: i32 = 0;
+fn scaleBicubicHermite(P: vec2<f32>, level: i32) -> vec4<f32> {
+    let imageSize = vec2<f32>(textureDimensions(t_diffuse, level));
+    let P1 = P * imageSize - 0.5;
+    let frac = fract(P1);
+    let pixel = vec2<i32>(floor(P1));
+
+    var CPX: array<vec4<f32>, 4>;
+
+    for (var y = 0; y < 4; y = y + 1) {
+        let row = pixel + vec2<i32>(0, y - 1);
+        CPX[y] = cubicHermite(
+            texel(row + vec2<i32>(-1, 0), level),
+            texel(row, level),
+            texel(row + vec2<i32>(1, 0), level),
+            texel(row + vec2<i32>(2, 0), level),
+            frac.x
+        );
+    }
 
+    return cubicHermite(CPX[0], CPX[1], CPX[2], CPX[3], frac.y);
+}
+
+// Lanczos kernel with two lobes
+fn lanczosWeight(x: f32) -> f32 {
+    if (abs(x) < 0.00001) {
+        return 1.0;
+    }
+    if (abs(x) >= 2.0) {
+        return 0.0;
+    }
+    let px = 3.14159265 * x;
+    return 2.0 * sin(px) * sin(px / 2.0) / (px * px);
+}
+
+fn scaleLanczos(P: vec2<f32>, level: i32) -> vec4<f32> {
+    let imageSize = vec2<f32>(textureDimensions(t_diffuse, level));
+    let P1 = P * imageSize - 0.5;
+    let frac = fract(P1);
+    let pixel = vec2<i32>(floor(P1));
+
+    var sum = vec4<f32>(0.0);
+    var total = 0.0;
     for (var y = -1; y <= 2; y = y + 1) {
+        let wy = lanczosWeight(frac.y - f32(y));
         for (var x = -1; x <= 2; x = x + 1) {
-            let offset = vec2<f32>(f32(y), f32(x)) * onePixel;
-            C[idx] = textureSample(t_diffuse, s_diffuse, pixel + offset).xyz;
-            idx = idx + 1;
+            let w = wy * lanczosWeight(frac.x - f32(x));
+            sum = sum + w * texel(pixel + vec2<i32>(x, y), level);
+            total = total + w;
         }
     }
+    return sum / total;
+}
 
-    var CPX: array<vec3<f32>, 4>;
+// side of the background squares, in screen pixels
+const CHECKER_SIZE = 8.0;
 
-    for (var i = 0; i < 4; i = i + 1) {
-        CPX[i] = cubicHermite(C[i], C[i+4], C[i+8], C[i+12], frac.x);
-    }
+// Narkowicz's fit of the ACES filmic curve
+fn acesFilm(x: vec3<f32>) -> vec3<f32> {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), vec3<f32>(0.0), vec3<f32>(1.0));
+}
 
-    return vec4(cubicHermite(CPX[0], CPX[1], CPX[2], CPX[3], frac.y), 1.0);
+// grey checkerboard in linear light, shown through transparent parts of the image
+fn checkerboard(position: vec2<f32>) -> vec3<f32> {
+    let square = vec2<i32>(floor(position / CHECKER_SIZE));
+    let dark = ((square.x + square.y) & 1) == 0;
+    return srgbToLinear(vec3<f32>(select(0.8, 0.6, dark)));
 }
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     var uv: vec2<f32> = in.tex_coords;
 
-    var result = scaleBicubicHermite(uv);
-    result.w = u.alpha;
+    // texels per screen pixel; when shrinking, filter a smaller mip level instead
+    let texels = uv * getImageSize();
+    let dx = dpdx(uv);
+    let dy = dpdy(uv);
+    let footprint = max(length(dpdx(texels)), length(dpdy(texels)));
+    let max_level = f32(textureNumLevels(t_diffuse) - 1u);
+    let level = i32(clamp(floor(log2(max(footprint, 1.0))), 0.0, max_level));
+
+    var result: vec4<f32>;
+    switch u.filter_mode {
+        case 0u: {
+            let size = vec2<f32>(textureDimensions(t_diffuse, level));
+            result = texel(vec2<i32>(floor(uv * size)), level);
+        }
+        case 1u: {
+            if (u.ycbcr != 0u) {
+                result = scaleBilinear(uv, level);
+            } else {
+                result = premultiply(textureSampleGrad(t_diffuse, s_diffuse, uv, dx, dy));
+            }
+        }
+        case 2u: {
+            result = scaleBicubicHermite(uv, level);
+        }
+        default: {
+            result = scaleLanczos(uv, level);
+        }
+    }
+
+    // sharpening filters overshoot, also in alpha
+    let coverage = clamp(result.w, 0.0, 1.0);
+    var color = max(result.xyz, vec3<f32>(0.0)) / max(result.w, 0.0001) * exp2(u.exposure);
+    switch u.tone_mapping {
+        case 1u: {
+            color = color / (1.0 + color);
+        }
+        case 2u: {
+            color = acesFilm(color);
+        }
+        default: {}
+    }
+    color = mix(checkerboard(in.clip_position.xy), color, coverage);
+    result = vec4(color, u.alpha);
 
     return result;
 }
@@ -0,0 +1,321 @@
+use crate::format::FileFormat;
+use rayon::prelude::*;
+use rexiv2::Metadata;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Keep the order in which paths were given
+    None,
+    /// File name, with numbers compared by value (IMG_9 before IMG_10)
+    Natural,
+    /// File name, byte by byte
+    Name,
+    /// Modification time
+    Mtime,
+    /// EXIF capture time (DateTimeOriginal)
+    Exif,
+    /// File size
+    Size,
+    /// Seeded shuffle
+    Random,
+}
+
+impl SortOrder {
+    /// The next order when cycling through them at runtime.
+    pub fn next(self) -> Self {
+        match self {
+            SortOrder::None => SortOrder::Natural,
+            SortOrder::Natural => SortOrder::Name,
+            SortOrder::Name => SortOrder::Mtime,
+            SortOrder::Mtime => SortOrder::Exif,
+            SortOrder::Exif => SortOrder::Size,
+            SortOrder::Size => SortOrder::Random,
+            SortOrder::Random => SortOrder::None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SortOrder::None => "none",
+            SortOrder::Natural => "natural",
+            SortOrder::Name => "name",
+            SortOrder::Mtime => "mtime",
+            SortOrder::Exif => "exif",
+            SortOrder::Size => "size",
+            SortOrder::Random => "random",
+        }
+    }
+}
+
+/// Variants are ordered so that files without a key sort last.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum SortKey {
+    Number(u64),
+    Text(String),
+    Missing,
+}
+
+fn natural_cmp_str(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                        digits.push(c);
+                    }
+                    digits.trim_start_matches('0').to_string()
+                };
+                let (x, y) = (take_number(&mut a), take_number(&mut b));
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Compare paths component by component, treating runs of digits as numbers.
+pub fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    let mut a_components = a.components();
+    let mut b_components = b.components();
+    loop {
+        match (a_components.next(), b_components.next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                let ordering = natural_cmp_str(
+                    &x.as_os_str().to_string_lossy(),
+                    &y.as_os_str().to_string_lossy(),
+                );
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+/// Stable pseudo-random key, so that files added later keep the shuffle order.
+fn shuffle_key(seed: u64, path: &Path) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in path.as_os_str().as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    // splitmix64 finalizer, spreads similar names across the sequence
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+fn exif_capture_time(path: &Path) -> Option<String> {
+    if !FileFormat::from_path(path)?.has_metadata() {
+        return None;
+    }
+    let metadata = Metadata::new_from_path(path).ok()?;
+    let date = metadata
+        .get_tag_string("Exif.Photo.DateTimeOriginal")
+        .ok()?;
+    let subsec = metadata
+        .get_tag_string("Exif.Photo.SubSecTimeOriginal")
+        .unwrap_or_default();
+    Some(format!("{}.{:0<9}", date.trim(), subsec.trim()))
+}
+
+/// Sorts image paths by the configured order. Keys that need file access
+/// are computed once, in parallel, and cached.
+pub struct Sorter {
+    pub order: SortOrder,
+    pub reverse: bool,
+    seed: u64,
+    keys: HashMap<PathBuf, SortKey>,
+    /// Position of each path in the order it was first seen, which `SortOrder::None` restores
+    given: HashMap<PathBuf, usize>,
+}
+
+impl Sorter {
+    pub fn new(order: SortOrder, reverse: bool, seed: u64) -> Self {
+        Self {
+            order,
+            reverse,
+            seed,
+            keys: HashMap::new(),
+            given: HashMap::new(),
+        }
+    }
+
+    fn compute_key(order: SortOrder, seed: u64, path: &Path) -> SortKey {
+        let metadata = || std::fs::metadata(path).ok();
+        match order {
+            SortOrder::Mtime => metadata()
+                .and_then(|m| m.modified().ok())
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| SortKey::Number(d.as_nanos() as u64))
+                .unwrap_or(SortKey::Missing),
+            SortOrder::Size => metadata()
+                .map(|m| SortKey::Number(m.len()))
+                .unwrap_or(SortKey::Missing),
+            SortOrder::Exif => exif_capture_time(path)
+                .map(SortKey::Text)
+                .unwrap_or(SortKey::Missing),
+            SortOrder::Random => SortKey::Number(shuffle_key(seed, path)),
+            SortOrder::None | SortOrder::Natural | SortOrder::Name => SortKey::Missing,
+        }
+    }
+
+    fn uses_keys(&self) -> bool {
+        matches!(
+            self.order,
+            SortOrder::Mtime | SortOrder::Exif | SortOrder::Size | SortOrder::Random
+        )
+    }
+
+    fn record_given<'a>(&mut self, paths: impl Iterator<Item = &'a PathBuf>) {
+        for path in paths {
+            let next = self.given.len();
+            self.given.entry(path.clone()).or_insert(next);
+        }
+    }
+
+    fn update_keys<'a>(&mut self, paths: impl Iterator<Item = &'a PathBuf>) {
+        if !self.uses_keys() {
+            return;
+        }
+        let missing: Vec<&PathBuf> = paths.filter(|p| !self.keys.contains_key(*p)).collect();
+        let (order, seed) = (self.order, self.seed);
+        let keys: Vec<(PathBuf, SortKey)> = missing
+            .into_par_iter()
+            .map(|path| (path.clone(), Self::compute_key(order, seed, path)))
+            .collect();
+        self.keys.extend(keys);
+    }
+
+    pub fn compare(&self, a: &Path, b: &Path) -> Ordering {
+        let ordering = match self.order {
+            SortOrder::None => self.given.get(a).cmp(&self.given.get(b)),
+            SortOrder::Natural => natural_cmp(a, b),
+            SortOrder::Name => a.cmp(b),
+            _ => self
+                .keys
+                .get(a)
+                .cmp(&self.keys.get(b))
+                .then_with(|| natural_cmp(a, b)),
+        };
+        if self.reverse {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+
+    /// Sort by the current order; ties keep their previous order.
+    pub fn sort_by_key<T>(&mut self, items: &mut [T], path: impl Fn(&T) -> &PathBuf) {
+        self.record_given(items.iter().map(&path));
+        self.update_keys(items.iter().map(&path));
+        items.sort_by(|a, b| self.compare(path(a), path(b)));
+    }
+
+    /// Switch to another order, dropping the keys of the previous one.
+    pub fn set_order(&mut self, order: SortOrder, reverse: bool) {
+        if order != self.order {
+            self.keys.clear();
+        }
+        self.order = order;
+        self.reverse = reverse;
+    }
+
+    /// Index at which `path` has to be inserted into the sorted `paths`,
+    /// or `None` if the order does not determine a position.
+    pub fn position<'a>(
+        &mut self,
+        paths: impl Iterator<Item = &'a PathBuf> + Clone,
+        path: &PathBuf,
+    ) -> Option<usize> {
+        self.record_given(std::iter::once(path));
+        if self.order == SortOrder::None {
+            return None;
+        }
+        self.update_keys(std::iter::once(path));
+        let mut count = 0;
+        for (i, other) in paths.enumerate() {
+            if self.compare(path, other) == Ordering::Less {
+                return Some(i);
+            }
+            count = i + 1;
+        }
+        Some(count)
+    }
+
+    /// Forget the cached key of a path, e.g. after the file changed.
+    pub fn forget(&mut self, path: &Path) {
+        self.keys.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn natural(a: &str, b: &str) -> Ordering {
+        natural_cmp(Path::new(a), Path::new(b))
+    }
+
+    #[test]
+    fn natural_order_compares_numbers_by_value() {
+        assert_eq!(natural("img2.jpg", "img10.jpg"), Ordering::Less);
+        assert_eq!(natural("IMG_10.jpg", "img_9.jpg"), Ordering::Greater);
+        // longer than any integer type
+        let long = "1".repeat(30);
+        assert_eq!(natural(&format!("{}0", long), &format!("{}1", long)), Ordering::Less);
+        assert_eq!(natural(&long, &format!("{}0", long)), Ordering::Less);
+        // equal numbers fall back to a total order
+        assert_eq!(natural("img007.jpg", "img7.jpg"), Ordering::Less);
+        assert_eq!(natural("img7.jpg", "img7.jpg"), Ordering::Equal);
+    }
+
+    #[test]
+    fn natural_order_is_case_insensitive_and_by_component() {
+        assert_eq!(natural("B.jpg", "a.jpg"), Ordering::Greater);
+        // byte-wise "a/" sorts after "a ", but the directory "a" comes before "a b"
+        assert_eq!(natural("a/z.jpg", "a b/a.jpg"), Ordering::Less);
+        assert_eq!(natural("a/2/x.jpg", "a/10/a.jpg"), Ordering::Less);
+    }
+
+    #[test]
+    fn none_restores_the_given_order() {
+        let given: Vec<PathBuf> = ["c.jpg", "a.jpg", "b.jpg", "d.jpg"].map(PathBuf::from).into();
+        let mut paths = given.clone();
+        let mut sorter = Sorter::new(SortOrder::None, false, 1);
+        sorter.sort_by_key(&mut paths, |p| p);
+        assert_eq!(paths, given);
+        sorter.set_order(SortOrder::Natural, false);
+        sorter.sort_by_key(&mut paths, |p| p);
+        assert_eq!(paths, ["a.jpg", "b.jpg", "c.jpg", "d.jpg"].map(PathBuf::from));
+        sorter.set_order(SortOrder::None, false);
+        sorter.sort_by_key(&mut paths, |p| p);
+        assert_eq!(paths, given);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::decode_queue::CancelToken;
+use anyhow::*;
+use image::{DynamicImage, RgbaImage};
+use log::debug;
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{fontdb, Options, Tree};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// System fonts for text in SVGs, loaded on first use.
+fn fonts() -> Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = fontdb::Database::new();
+            fonts.load_system_fonts();
+            debug!("loaded {} font faces", fonts.len());
+            Arc::new(fonts)
+        })
+        .clone()
+}
+
+/// Parse an SVG or gzipped SVGZ document; relative references resolve next to `path`.
+fn parse(path: &Path, bytes: &[u8]) -> Result<Tree> {
+    let options = Options {
+        resources_dir: path.parent().map(Path::to_path_buf),
+        fontdb: fonts(),
+        ..Options::default()
+    };
+    Tree::from_data(bytes, &options).map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+/// Rasterise an SVG to the largest size that fits into `target`, keeping its aspect ratio.
+pub fn render(
+    path: &Path,
+    bytes: &[u8],
+    target: (u32, u32),
+    cancel: &CancelToken,
+) -> Result<DynamicImage> {
+    let tree = parse(path, bytes)?;
+    // rasterising cannot be interrupted, so give up before it starts
+    cancel.check()?;
+    let size = tree.size();
+    let scale = (target.0 as f32 / size.width()).min(target.1 as f32 / size.height());
+    let width = ((size.width() * scale).round() as u32).max(1);
+    let height = ((size.height() * scale).round() as u32).max(1);
+    let mut pixmap =
+        Pixmap::new(width, height).ok_or(anyhow!("invalid SVG size {}x{}", width, height))?;
+    resvg::render(
+        &tree,
+        Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    debug!("{}: rendered at {}x{}", path.display(), width, height);
+    // tiny-skia works with premultiplied alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    Ok(DynamicImage::ImageRgba8(
+        RgbaImage::from_vec(width, height, pixels).ok_or(anyhow!("SVG buffer size mismatch"))?,
+    ))
+}
@@ -1,12 +1,33 @@
+use crate::animation;
+use crate::archive;
+use crate::buffer_pool;
+use crate::color::{self, ColorManagement};
+use crate::decode_queue::CancelToken;
+use crate::format::FileFormat;
 use crate::image_loader::ImageRef;
 use crate::image_loader::ImageRequest;
+use crate::load_error::LoadError;
+use crate::pages;
+use crate::raw;
+use crate::svg;
+use crate::thumbnail_cache::{ThumbnailCache, ThumbnailSize};
 use anyhow::*;
-use image::{DynamicImage, ImageBuffer};
-use log::{debug, error};
+use half::f16;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageBuffer, RgbaImage};
+use log::{debug, error, warn};
 use logging_timer::{executing, time, timer};
+use memmap2::Mmap;
 use number_prefix::NumberPrefix;
+use qcms::Profile;
 use rexiv2::{Metadata, Orientation};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 #[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub enum ImageResolution {
@@ -15,6 +36,312 @@ pub enum ImageResolution {
     NATIVE,
 }
 
+/// How the shader samples images, passed to `shader.wgsl` as a uniform.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    /// Nearest pixel, for pixel peeping
+    Nearest,
+    /// Bilinear between mipmap levels
+    Bilinear,
+    /// Bicubic (Catmull-Rom)
+    Bicubic,
+    /// Lanczos with two lobes
+    Lanczos,
+}
+
+impl FilterMode {
+    pub fn next(self) -> Self {
+        match self {
+            FilterMode::Nearest => FilterMode::Bilinear,
+            FilterMode::Bilinear => FilterMode::Bicubic,
+            FilterMode::Bicubic => FilterMode::Lanczos,
+            FilterMode::Lanczos => FilterMode::Nearest,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterMode::Nearest => "nearest",
+            FilterMode::Bilinear => "bilinear",
+            FilterMode::Bicubic => "bicubic",
+            FilterMode::Lanczos => "lanczos",
+        }
+    }
+}
+
+/// How the shader maps linear light to the display, after exposure.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapping {
+    /// Clip values above white
+    None,
+    /// Reinhard, `c / (1 + c)`
+    Reinhard,
+    /// ACES filmic curve (Narkowicz fit)
+    Aces,
+}
+
+impl ToneMapping {
+    pub fn next(self) -> Self {
+        match self {
+            ToneMapping::None => ToneMapping::Reinhard,
+            ToneMapping::Reinhard => ToneMapping::Aces,
+            ToneMapping::Aces => ToneMapping::None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ToneMapping::None => "none",
+            ToneMapping::Reinhard => "reinhard",
+            ToneMapping::Aces => "aces",
+        }
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Linear value for every 16-bit sRGB value, built on first use.
+fn srgb16_to_linear_lut() -> &'static [f16] {
+    static LUT: OnceLock<Vec<f16>> = OnceLock::new();
+    LUT.get_or_init(|| {
+        (0..=u16::MAX)
+            .map(|v| f16::from_f32(srgb_to_linear(v as f32 / 65535.0)))
+            .collect()
+    })
+}
+
+/// Decoded pixels in the layout they are uploaded in.
+#[derive(Debug)]
+pub enum Pixels {
+    /// 8-bit sRGB, uploaded as `Rgba8UnormSrgb`
+    Srgb8(RgbaImage),
+    /// Linear half floats, uploaded as `Rgba16Float`; HDR values may exceed 1.0
+    LinearF16 {
+        width: u32,
+        height: u32,
+        data: Vec<f16>,
+    },
+    /// 8-bit Y'CbCr planes of a JPEG, uploaded as one `R8Unorm` texture each and
+    /// converted to RGB by the shader. The chroma planes keep the JPEG's subsampling,
+    /// all planes are padded to whole chroma samples. The shader converts each texel
+    /// before filtering, but the smaller mip levels are averaged from gamma-encoded
+    /// values, so minified fine detail can come out slightly darker than from `Srgb8`.
+    YCbCr {
+        width: u32,
+        height: u32,
+        /// Luma samples per chroma sample, horizontally and vertically
+        subsampling: (u32, u32),
+        data: Vec<u8>,
+    },
+}
+
+/// 8-bit buffers go back to the pool for the next decode.
+impl Drop for Pixels {
+    fn drop(&mut self) {
+        match self {
+            Pixels::Srgb8(image) => {
+                buffer_pool::recycle(std::mem::replace(image, RgbaImage::new(0, 0)).into_raw())
+            }
+            Pixels::YCbCr { data, .. } => buffer_pool::recycle(std::mem::take(data)),
+            Pixels::LinearF16 { .. } => {}
+        }
+    }
+}
+
+/// One plane of `Pixels`, uploaded as its own texture.
+pub struct Plane<'a> {
+    pub bytes: &'a [u8],
+    pub format: wgpu::TextureFormat,
+    pub bytes_per_pixel: u32,
+    /// Row length in pixels
+    pub width: u32,
+    /// Image pixels per plane pixel, horizontally and vertically
+    pub subsampling: (u32, u32),
+}
+
+impl Pixels {
+    /// 16-bit and floating point images keep their precision, anything else becomes 8-bit.
+    pub fn from_image(image: DynamicImage) -> Self {
+        match image {
+            DynamicImage::ImageRgba8(image) => Pixels::Srgb8(image),
+            DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => {
+                let image = image.to_rgba16();
+                let lut = srgb16_to_linear_lut();
+                let data = image
+                    .pixels()
+                    .flat_map(|p| {
+                        let [r, g, b, a] = p.0;
+                        [
+                            lut[r as usize],
+                            lut[g as usize],
+                            lut[b as usize],
+                            f16::from_f32(a as f32 / 65535.0),
+                        ]
+                    })
+                    .collect();
+                Pixels::LinearF16 {
+                    width: image.width(),
+                    height: image.height(),
+                    data,
+                }
+            }
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                let image = image.to_rgba32f();
+                // out of range values would turn into infinity
+                let max = f16::MAX.to_f32();
+                let data = image
+                    .as_raw()
+                    .iter()
+                    .map(|&v| f16::from_f32(v.clamp(0.0, max)))
+                    .collect();
+                Pixels::LinearF16 {
+                    width: image.width(),
+                    height: image.height(),
+                    data,
+                }
+            }
+            image => Pixels::Srgb8(image.to_rgba8()),
+        }
+    }
+
+    /// The image of `Srgb8` pixels, whose buffer then no longer returns to the pool.
+    pub fn into_srgb8(mut self) -> Option<RgbaImage> {
+        match &mut self {
+            Pixels::Srgb8(image) => Some(std::mem::replace(image, RgbaImage::new(0, 0))),
+            _ => None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.dimensions().0
+    }
+
+    pub fn height(&self) -> u32 {
+        self.dimensions().1
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Pixels::Srgb8(image) => image.dimensions(),
+            Pixels::LinearF16 { width, height, .. } | Pixels::YCbCr { width, height, .. } => {
+                (*width, *height)
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Pixels::Srgb8(image) => image.as_raw(),
+            Pixels::LinearF16 { data, .. } => bytemuck::cast_slice(data),
+            Pixels::YCbCr { data, .. } => data,
+        }
+    }
+
+    /// Bytes allocated for the pixels, which pooled buffers may exceed the pixels by.
+    pub fn allocated_bytes(&self) -> usize {
+        match self {
+            Pixels::Srgb8(image) => image.as_raw().capacity(),
+            Pixels::LinearF16 { data, .. } => data.capacity() * std::mem::size_of::<f16>(),
+            Pixels::YCbCr { data, .. } => data.capacity(),
+        }
+    }
+
+    /// Whether the shader has to convert the planes to RGB.
+    pub fn is_ycbcr(&self) -> bool {
+        matches!(self, Pixels::YCbCr { .. })
+    }
+
+    /// Multiple of which tiles have to start and end, so that each chroma
+    /// sample is uploaded with all of its luma samples.
+    pub fn block_size(&self) -> (u32, u32) {
+        match self {
+            Pixels::YCbCr { subsampling, .. } => *subsampling,
+            _ => (1, 1),
+        }
+    }
+
+    /// The textures to upload: RGBA as a single plane, Y'CbCr as three.
+    pub fn planes(&self) -> Vec<Plane<'_>> {
+        match self {
+            Pixels::Srgb8(_) | Pixels::LinearF16 { .. } => {
+                let (format, bytes_per_pixel) = match self {
+                    Pixels::Srgb8(_) => (wgpu::TextureFormat::Rgba8UnormSrgb, 4),
+                    _ => (wgpu::TextureFormat::Rgba16Float, 8),
+                };
+                vec![Plane {
+                    bytes: self.as_bytes(),
+                    format,
+                    bytes_per_pixel,
+                    width: self.width(),
+                    subsampling: (1, 1),
+                }]
+            }
+            Pixels::YCbCr {
+                width,
+                height,
+                subsampling: (sx, sy),
+                data,
+            } => {
+                let luma = (width.next_multiple_of(*sx), height.next_multiple_of(*sy));
+                let chroma = (luma.0 / sx, luma.1 / sy);
+                let luma_len = (luma.0 * luma.1) as usize;
+                let chroma_len = (chroma.0 * chroma.1) as usize;
+                let plane = |bytes, width, subsampling| Plane {
+                    bytes,
+                    format: wgpu::TextureFormat::R8Unorm,
+                    bytes_per_pixel: 1,
+                    width,
+                    subsampling,
+                };
+                vec![
+                    plane(&data[..luma_len], luma.0, (1, 1)),
+                    plane(&data[luma_len..][..chroma_len], chroma.0, (*sx, *sy)),
+                    plane(&data[luma_len + chroma_len..][..chroma_len], chroma.0, (*sx, *sy)),
+                ]
+            }
+        }
+    }
+}
+
+/// 8-bit sRGB version of an image for display or storage. Float images hold
+/// linear light, which is clipped and encoded; integer images are already sRGB.
+pub fn to_srgb8(image: &DynamicImage) -> RgbaImage {
+    match image {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            let mut image = image.to_rgba32f();
+            for p in image.pixels_mut() {
+                for c in &mut p.0[..3] {
+                    *c = linear_to_srgb(c.clamp(0.0, 1.0));
+                }
+            }
+            DynamicImage::ImageRgba32F(image).to_rgba8()
+        }
+        image => image.to_rgba8(),
+    }
+}
+
+/// Number of mipmap levels down to 1x1.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -25,8 +352,8 @@ pub struct Texture {
 pub enum ColorSpace {
     Rgb,
     Rgba,
+    /// Planes as stored in the JPEG, see `Pixels::YCbCr`
     Yuv,
-    Raw,
 }
 
 pub struct DecodeStats {
@@ -60,161 +387,239 @@ impl DecodeStats {
 }
 
 impl Texture {
-    #[time]
-    pub fn from_rgba(
+    /// Create a texture without contents, to be filled by copies, with a
+    /// mip chain for `MipmapGenerator` to fill after them.
+    pub fn empty(
         device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        image: DynamicImage,
+        format: wgpu::TextureFormat,
+        (width, height): (u32, u32),
         label: Option<&str>,
-    ) -> Result<Self> {
-        let tmr = timer!("creating texture");
-        let size = wgpu::Extent3d {
-            width: image.width(),
-            height: image.height(),
-            depth_or_array_layers: 1,
-        };
-        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+    ) -> Self {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
-            size,
-            mip_level_count: 1,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: mip_level_count(width, height),
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::RENDER_ATTACHMENT,
             view_formats: &[],
         });
-        executing!(tmr, "texture created");
-
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            image.as_bytes(),
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * image.width()),
-                rows_per_image: Some(image.height()),
-            },
-            size,
-        );
-        executing!(tmr, "texture written");
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        executing!(tmr, "texture view created");
 
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
             ..Default::default()
         });
-        executing!(tmr, "sampler created");
 
-        Ok(Self {
+        Self {
             texture,
             view,
             sampler,
-        })
+        }
     }
+}
 
-    #[time]
-    pub fn from_image(
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        img: DynamicImage,
-        label: Option<&str>,
-    ) -> Result<Self> {
-        Self::from_rgba(device, queue, img, label)
-    }
+/// JPEG scaling factors supported by the DCT-domain decoder, in eighths.
+pub const JPEG_SCALES: [u8; 4] = [1, 2, 4, 8];
 
-    pub fn decode_turbojpeg(
-        bytes: &[u8],
-        format: turbojpeg::PixelFormat,
-    ) -> Result<(u32, u32, Vec<u8>)> {
-        let img = turbojpeg::decompress(bytes, format)?;
-        Ok((img.width as u32, img.height as u32, img.pixels))
-    }
+/// Size of a JPEG dimension after DCT scaling by `scale`/8 (same rounding as libjpeg-turbo).
+pub fn jpeg_scaled_dimension(dim: u32, scale: u8) -> u32 {
+    (dim * scale as u32).div_ceil(8)
 }
 
+/// Pick the smallest JPEG scale whose output still covers `target` when fitted to it,
+/// i.e. displaying the result at zoom 1 never upscales.
+pub fn jpeg_scale_for_target(size: (u32, u32), target: (u32, u32)) -> u8 {
+    JPEG_SCALES
+        .iter()
+        .copied()
+        .find(|&scale| {
+            jpeg_scaled_dimension(size.0, scale) >= target.0
+                || jpeg_scaled_dimension(size.1, scale) >= target.1
+        })
+        .unwrap_or(8)
+}
+
+pub fn read_jpeg_size(bytes: &[u8]) -> Result<(u32, u32)> {
+    let header = turbojpeg::read_header(bytes)?;
+    Ok((header.width as u32, header.height as u32))
+}
+
+/// libjpeg warnings about truncated or damaged data, which it decodes as far as it goes.
+const JPEG_WARNINGS: [&str; 2] = ["Premature end of JPEG file", "Corrupt JPEG data"];
+
+/// Decode a JPEG, scaled by `scale`/8 in the DCT domain (1, 2, 4 or 8), to RGBA, or
+/// with `ColorSpace::Yuv` to its Y'CbCr planes, skipping the colour conversion.
+/// Only colour JPEGs stored as Y'CbCr have planes, for others that is `None`.
+/// Truncated files are decoded partially, as other viewers do.
 pub fn decode_turbojpeg(
     bytes: &[u8],
     scale: u8,
     color_space: ColorSpace,
-) -> Result<(u32, u32, Vec<u8>)> {
-    if scale != 8 {
-        return Err(anyhow!("Unsupported scale"));
-    }
-    let format = match color_space {
-        ColorSpace::Rgb => Ok(turbojpeg::PixelFormat::RGB),
-        ColorSpace::Rgba => Ok(turbojpeg::PixelFormat::RGBA),
-        _ => Err(anyhow!("Unsupported color space")),
-    }?;
+) -> Result<Option<Pixels>> {
+    if !JPEG_SCALES.contains(&scale) {
+        return Err(anyhow!("Unsupported scale {}/8", scale));
+    }
+    if !matches!(color_space, ColorSpace::Rgba | ColorSpace::Yuv) {
+        return Err(anyhow!("Unsupported color space"));
+    }
     let result = std::panic::catch_unwind(|| {
         let tmr = timer!("Decompress JPEG");
-        let img = turbojpeg::decompress(bytes, format)?;
+        let mut decompressor = turbojpeg::Decompressor::new()?;
+        let header = decompressor.read_header(bytes)?;
         executing!(tmr, "decompress init complete");
-        let (w, h) = (img.width as u32, img.height as u32);
-        executing!(tmr, "read scanlines complete {:?}", format);
-        Ok((w, h, img.pixels))
+        let w = jpeg_scaled_dimension(header.width as u32, scale);
+        let h = jpeg_scaled_dimension(header.height as u32, scale);
+        let pixels = match color_space {
+            ColorSpace::Yuv => decompress_ycbcr(&mut decompressor, &header, bytes, (w, h))?,
+            _ => Some(decompress_rgba(&mut decompressor, bytes, (w, h))?),
+        };
+        executing!(tmr, "read scanlines complete {:?} scale={}/8", color_space, scale);
+        Ok(pixels)
     })
     .map_err(|err| anyhow!("Failed to decompress JPEG: {:?}", err))?;
 
     result
 }
 
+fn decompress_rgba(
+    decompressor: &mut turbojpeg::Decompressor,
+    bytes: &[u8],
+    (w, h): (u32, u32),
+) -> Result<Pixels> {
+    let format = turbojpeg::PixelFormat::RGBA;
+    // tjDecompress2 picks the largest scaling factor that fits into the output size
+    let mut img = turbojpeg::Image {
+        pixels: buffer_pool::take(format.size() * w as usize * h as usize),
+        width: w as usize,
+        pitch: format.size() * w as usize,
+        height: h as usize,
+        format,
+    };
+    allow_jpeg_warnings(decompressor.decompress(bytes, img.as_deref_mut()))?;
+    let image = ImageBuffer::from_vec(w, h, img.pixels);
+    Ok(Pixels::Srgb8(image.ok_or(anyhow!("JPEG buffer size mismatch"))?))
+}
+
+fn decompress_ycbcr(
+    decompressor: &mut turbojpeg::Decompressor,
+    header: &turbojpeg::DecompressHeader,
+    bytes: &[u8],
+    (w, h): (u32, u32),
+) -> Result<Option<Pixels>> {
+    if header.colorspace != turbojpeg::Colorspace::YCbCr
+        || header.subsamp == turbojpeg::Subsamp::Gray
+    {
+        return Ok(None);
+    }
+    // rows are not padded, so that each plane can be uploaded as it is
+    let align = 1;
+    let len = turbojpeg::yuv_pixels_len(w as usize, align, h as usize, header.subsamp)?;
+    let mut img = turbojpeg::YuvImage {
+        pixels: buffer_pool::take(len),
+        width: w as usize,
+        align,
+        height: h as usize,
+        subsamp: header.subsamp,
+    };
+    allow_jpeg_warnings(decompressor.decompress_to_yuv(bytes, img.as_deref_mut()))?;
+    let (sx, sy) = header.subsamp.size();
+    Ok(Some(Pixels::YCbCr {
+        width: w,
+        height: h,
+        subsampling: (sx as u32, sy as u32),
+        data: img.pixels,
+    }))
+}
+
+/// After a warning the whole image has been decoded, missing parts are grey.
+fn allow_jpeg_warnings(result: Result<(), turbojpeg::Error>) -> Result<()> {
+    match result {
+        Result::Ok(()) => Ok(()),
+        Err(turbojpeg::Error::TurboJpegError(message))
+            if JPEG_WARNINGS.iter().any(|w| message.starts_with(w)) =>
+        {
+            warn!("partially decoded JPEG: {}", message);
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 pub fn load_image_thumbnail_bytes(metadata: &Metadata) -> Option<Vec<u8>> {
     metadata.get_thumbnail().map(|t| t.to_vec())
 }
 
-#[time]
-pub fn load_image_bytes(path: PathBuf) -> Vec<u8> {
-    use std::fs::File;
-    use std::io::Read;
-    let mut file = File::open(&path).unwrap();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    buffer
+/// The contents of an image file: mapped into memory, or read where that fails
+/// and for archive entries.
+pub enum FileBytes {
+    Mapped(Mmap),
+    Read(Vec<u8>),
 }
 
-fn check_color_space(path: &PathBuf, metadata: &Metadata) {
-    match metadata.get_tag_string("Exif.Photo.ColorSpace") {
-        Result::Ok(s) if s.eq("1") => {
-            debug!("{}: Color space: sRGB", path.display());
-        }
-        Result::Ok(s) => {
-            error!("{}: Unknown color space: {}", path.display(), s)
-        }
-        Err(e) => {
-            error!("{}: Failed to get color space: {}", path.display(), e)
+impl Deref for FileBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileBytes::Mapped(map) => map,
+            FileBytes::Read(bytes) => bytes,
         }
     }
 }
 
-pub fn get_rgba_for_path(
-    path: PathBuf,
-    resolution: &ImageResolution,
-) -> Result<(DynamicImage, Orientation)> {
-    let metadata = Metadata::new_from_path(&path)?;
-    check_color_space(&path, &metadata);
-    let orientation = metadata.get_orientation();
-    let img_bytes = match resolution {
-        ImageResolution::THUMBNAIL => {
-            load_image_thumbnail_bytes(&metadata).unwrap_or_else(|| load_image_bytes(path.clone()))
+/// Files modified more recently than this are read rather than mapped, as they
+/// may still be rewritten, e.g. by a copy over them or a tethered camera.
+const MAP_MIN_AGE: Duration = Duration::from_secs(5);
+
+/// Map a file into memory; the metadata parser and the decoder read it straight
+/// from the page cache, and only the parts they touch. Recently modified files
+/// are read into memory instead.
+#[time]
+pub fn load_image_bytes(path: &Path) -> Result<FileBytes> {
+    let mut file = File::open(path).map_err(LoadError::from)?;
+    let settled = file
+        .metadata()
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age >= MAP_MIN_AGE);
+    if settled {
+        // Safety: the mapping is only read, but nothing stops another program
+        // from truncating the file while it is mapped, e.g. `cp` or an editor
+        // saving over it. Reading the truncated part then raises SIGBUS and ends
+        // the process. Only files that have not changed for a while are mapped,
+        // which makes that unlikely, not impossible.
+        match unsafe { Mmap::map(&file) } {
+            Result::Ok(map) => return Ok(FileBytes::Mapped(map)),
+            Err(e) => debug!("{}: cannot map, reading instead: {}", path.display(), e),
         }
-        ImageResolution::NATIVE => load_image_bytes(path.clone()),
-        ImageResolution::FULLHD => todo!(),
-    };
+    }
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).map_err(LoadError::from)?;
+    Ok(FileBytes::Read(bytes))
+}
+
+fn decode_jpeg(bytes: &[u8], scale: u8) -> Result<DynamicImage> {
     let start_time = std::time::Instant::now();
-    let (w, h, bytes) = decode_turbojpeg(&img_bytes, 8, ColorSpace::Rgba)?;
+    let pixels = decode_turbojpeg(bytes, scale, ColorSpace::Rgba)?
+        .ok_or(anyhow!("JPEG not decoded to RGBA"))?;
+    let (w, h) = pixels.dimensions();
     let elapsed = start_time.elapsed();
-    let decode_stats = DecodeStats::new(bytes.len(), elapsed);
+    let decode_stats = DecodeStats::new(pixels.as_bytes().len(), elapsed);
     debug!(
         "Decompressed JPEG, {}x{}px, {}ms, {}, {}",
         w,
@@ -223,31 +628,656 @@ pub fn get_rgba_for_path(
         decode_stats.bytes_si(),
         decode_stats.bytes_per_sec_si()
     );
-    Ok((
-        DynamicImage::ImageRgba8(ImageBuffer::from_vec(w, h, bytes).unwrap()),
+    let image = pixels.into_srgb8().ok_or(anyhow!("JPEG not decoded to RGBA"))?;
+    Ok(DynamicImage::ImageRgba8(image))
+}
+
+#[time]
+fn decode_with_image_crate(bytes: &[u8], format: FileFormat) -> Result<DynamicImage> {
+    let mut reader = image::io::Reader::with_format(std::io::Cursor::new(bytes), format.try_into()?);
+    // images larger than a texture are tiled, so only memory limits their size
+    reader.no_limits();
+    Ok(to_rgba_depth(reader.decode()?))
+}
+
+/// Convert to RGBA, keeping 16-bit and float precision.
+/// 16-bit and float images are converted to half floats by `Pixels`.
+fn to_rgba_depth(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgba16(img.to_rgba16()),
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            DynamicImage::ImageRgba32F(img.to_rgba32f())
+        }
+        img => DynamicImage::ImageRgba8(img.to_rgba8()),
+    }
+}
+
+/// Decode image bytes to RGBA, dispatching on the detected format.
+/// JPEG goes through turbojpeg, everything else through the `image` crate.
+pub fn decode_image(bytes: &[u8]) -> Result<DynamicImage> {
+    match FileFormat::from_magic(bytes) {
+        Some(FileFormat::Jpeg) => decode_jpeg(bytes, 8),
+        Some(format) => decode_with_image_crate(bytes, format),
+        None => Err(anyhow!("Unknown image format")),
+    }
+}
+
+/// Decode image bytes at the smallest size that still covers `target`.
+/// Only JPEG supports cheap DCT scaling; other formats are decoded at native size.
+pub fn decode_image_for_target(
+    bytes: &[u8],
+    target: (u32, u32),
+) -> Result<(DynamicImage, (u32, u32))> {
+    match FileFormat::from_magic(bytes) {
+        Some(FileFormat::Jpeg) => {
+            let size = read_jpeg_size(bytes)?;
+            let image = decode_jpeg(bytes, jpeg_scale_for_target(size, target))?;
+            Ok((image, size))
+        }
+        _ => {
+            let image = decode_image(bytes)?;
+            let size = image.dimensions();
+            Ok((image, size))
+        }
+    }
+}
+
+/// Bounding box for thumbnails that are decoded from the full image.
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+/// Decode a JPEG to Y'CbCr planes at the smallest scale that covers `target`, or
+/// at native size without one. Returns `None` for other formats, and when colours
+/// need converting to the display, which only works on RGB.
+fn decode_jpeg_ycbcr(
+    bytes: &[u8],
+    target: Option<(u32, u32)>,
+    color: &ColorManagement,
+    profile: Option<&Profile>,
+) -> Result<Option<(Pixels, (u32, u32))>> {
+    if FileFormat::from_magic(bytes) != Some(FileFormat::Jpeg) || !color.is_passthrough(profile) {
+        return Ok(None);
+    }
+    let size = read_jpeg_size(bytes)?;
+    let scale = target.map_or(8, |target| jpeg_scale_for_target(size, target));
+    Ok(decode_turbojpeg(bytes, scale, ColorSpace::Yuv)?.map(|pixels| (pixels, size)))
+}
+
+/// Decode a small preview from the full image bytes, for files without an embedded thumbnail.
+fn decode_thumbnail(bytes: &[u8]) -> Result<DynamicImage> {
+    match FileFormat::from_magic(bytes) {
+        Some(FileFormat::Jpeg) => {
+            let size = read_jpeg_size(bytes)?;
+            decode_jpeg(
+                bytes,
+                jpeg_scale_for_target(size, (THUMBNAIL_SIZE, THUMBNAIL_SIZE)),
+            )
+        }
+        _ => Ok(decode_image(bytes)?.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)),
+    }
+}
+
+fn metadata_pixel_size(metadata: &Metadata) -> Option<(u32, u32)> {
+    let (w, h) = (metadata.get_pixel_width(), metadata.get_pixel_height());
+    if w > 0 && h > 0 {
+        Some((w as u32, h as u32))
+    } else {
+        None
+    }
+}
+
+/// Crop the letterbox bars that cameras add when the embedded thumbnail
+/// has a different aspect ratio than the image itself.
+fn crop_to_aspect(image: DynamicImage, size: (u32, u32)) -> DynamicImage {
+    let (w, h) = image.dimensions();
+    let target = size.0 as f64 / size.1 as f64;
+    let actual = w as f64 / h as f64;
+    if (actual / target - 1.0).abs() < 0.01 {
+        return image;
+    }
+    if actual > target {
+        let crop_w = ((h as f64 * target).round() as u32).clamp(1, w);
+        image.crop_imm((w - crop_w) / 2, 0, crop_w, h)
+    } else {
+        let crop_h = ((w as f64 / target).round() as u32).clamp(1, h);
+        image.crop_imm(0, (h - crop_h) / 2, w, crop_h)
+    }
+}
+
+fn is_rotated(orientation: Orientation) -> bool {
+    matches!(
         orientation,
-    ))
+        Orientation::Rotate90
+            | Orientation::Rotate270
+            | Orientation::Rotate90HorizontalFlip
+            | Orientation::Rotate90VerticalFlip
+    )
 }
 
-#[derive(Debug)]
+/// Rotate and flip the image so that it is upright.
+pub fn apply_orientation(image: DynamicImage, orientation: Orientation) -> DynamicImage {
+    match orientation {
+        Orientation::Normal | Orientation::Unspecified => image,
+        Orientation::HorizontalFlip => image.fliph(),
+        Orientation::Rotate180 => image.rotate180(),
+        Orientation::VerticalFlip => image.flipv(),
+        Orientation::Rotate90HorizontalFlip => image.rotate90().fliph(),
+        Orientation::Rotate90 => image.rotate90(),
+        Orientation::Rotate90VerticalFlip => image.rotate90().flipv(),
+        Orientation::Rotate270 => image.rotate270(),
+    }
+}
+
+fn oriented_size(size: (u32, u32), orientation: Orientation) -> (u32, u32) {
+    if is_rotated(orientation) {
+        (size.1, size.0)
+    } else {
+        size
+    }
+}
+
+/// Write a thumbnail to the on-disk cache; failures are logged and otherwise ignored.
+/// The cache is shared with other applications, so thumbnails are stored in sRGB.
+fn store_thumbnail(
+    thumbnails: &ThumbnailCache,
+    color: &ColorManagement,
+    profile: Option<&Profile>,
+    path: &Path,
+    thumbnail: DynamicImage,
+    orientation: Orientation,
+    size: (u32, u32),
+) {
+    let thumbnail = color.to_srgb(apply_orientation(thumbnail, orientation), profile);
+    thumbnails
+        .store(
+            path,
+            &DynamicImage::ImageRgba8(to_srgb8(&thumbnail)),
+            oriented_size(size, orientation),
+        )
+        .map_err(|e| error!("{}: failed to store thumbnail: {}", path.display(), e))
+        .ok();
+}
+
+fn load_cached_thumbnail(
+    thumbnails: &ThumbnailCache,
+    path: &Path,
+) -> Option<(DynamicImage, Orientation, (u32, u32))> {
+    let cached = thumbnails
+        .load(path)
+        .map_err(|e| debug!("{}: failed to load thumbnail: {}", path.display(), e))
+        .ok()??;
+    let size = cached
+        .original_size
+        .unwrap_or_else(|| cached.image.dimensions());
+    // cached thumbnails are stored upright
+    Some((cached.image, Orientation::Normal, size))
+}
+
+/// The bytes of an image, shared by the metadata parser and the decoder:
+/// its own file mapped into memory, or an archive entry read into memory.
+struct ImageSource<'a> {
+    path: &'a Path,
+    bytes: FileBytes,
+    in_archive: bool,
+    /// Sub-image of a multi-page file
+    page: usize,
+}
+
+impl<'a> ImageSource<'a> {
+    fn new(iref: &'a ImageRef) -> Result<Self> {
+        let bytes = match &iref.entry {
+            Some(entry) => FileBytes::Read(archive::read_entry(entry)?),
+            None => load_image_bytes(&iref.path)?,
+        };
+        Ok(Self {
+            path: &iref.path,
+            bytes,
+            in_archive: iref.entry.is_some(),
+            page: iref.page,
+        })
+    }
+
+    /// Whether the image is a file that can be opened by path, e.g. by the RAW decoder.
+    fn is_file(&self) -> bool {
+        !self.in_archive
+    }
+
+    fn format(&self) -> Result<FileFormat> {
+        FileFormat::from_header(self.path, &self.bytes)
+            .ok_or(LoadError::Unsupported("unknown file type".to_string()).into())
+    }
+
+    fn metadata(&self, format: FileFormat) -> Option<Metadata> {
+        if !format.has_metadata() {
+            return None;
+        }
+        Metadata::new_from_buffer(&self.bytes)
+            .map_err(|e| debug!("{}: no metadata: {}", self.path.display(), e))
+            .ok()
+    }
+
+    fn icc_profile(&self, format: FileFormat) -> Option<Vec<u8>> {
+        color::read_icc_profile_from(self.path, Cursor::new(self.bytes()), format)
+    }
+
+    /// The whole file or entry.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Bytes to decode: the image itself, or the largest embedded preview of a RAW file.
+    fn decodable_bytes(
+        &self,
+        format: FileFormat,
+        metadata: Option<&Metadata>,
+    ) -> Result<Cow<'_, [u8]>> {
+        if format == FileFormat::Raw {
+            return metadata
+                .and_then(raw::largest_preview)
+                .map(Cow::Owned)
+                .ok_or(anyhow!("{}: no embedded preview", self.path.display()));
+        }
+        Ok(Cow::Borrowed(self.bytes()))
+    }
+}
+
+/// A decoded image with how long it is shown; still images have a single frame.
+pub type DecodedFrames = Vec<(DynamicImage, Duration)>;
+
+fn still(image: DynamicImage) -> DecodedFrames {
+    vec![(image, Duration::ZERO)]
+}
+
+/// Convert decoded frames to the display's colour space, ready for upload.
+fn frames_to_display(
+    color: &ColorManagement,
+    frames: DecodedFrames,
+    profile: Option<&Profile>,
+) -> Vec<Frame> {
+    frames
+        .into_iter()
+        .map(|(image, delay)| Frame {
+            pixels: Pixels::from_image(color.to_display(image, profile)),
+            delay,
+        })
+        .collect()
+}
+
+/// Orientation numbered as in TIFF and EXIF.
+fn orientation_from_tag(value: u16) -> Orientation {
+    match value {
+        2 => Orientation::HorizontalFlip,
+        3 => Orientation::Rotate180,
+        4 => Orientation::VerticalFlip,
+        5 => Orientation::Rotate90HorizontalFlip,
+        6 => Orientation::Rotate90,
+        7 => Orientation::Rotate90VerticalFlip,
+        8 => Orientation::Rotate270,
+        _ => Orientation::Normal,
+    }
+}
+
+/// Decode sub-image `page` (after the first) of a multi-page TIFF or an MPO file,
+/// with the colour profile and orientation of that page. The embedded and cached
+/// thumbnails only show the first page, so pages are always decoded from the file.
+fn get_page_frames(
+    source: &ImageSource,
+    page: usize,
+    format: FileFormat,
+    resolution: &ImageResolution,
+    target: (u32, u32),
+    color: &ColorManagement,
+    cancel: &CancelToken,
+) -> Result<(Vec<Frame>, Orientation, (u32, u32))> {
+    let path = source.path;
+    let bytes = source.bytes();
+    let (image, orientation, size, profile) = match format {
+        FileFormat::Jpeg => {
+            // each image of an MPO carries its own EXIF data and ICC profile
+            let bytes = pages::mpo_page(bytes, page)?;
+            let metadata = Metadata::new_from_buffer(bytes).ok();
+            let orientation = metadata
+                .as_ref()
+                .map(|m| m.get_orientation())
+                .unwrap_or(Orientation::Normal);
+            let icc = color::read_icc_profile_from(path, Cursor::new(bytes), format);
+            let profile = color.source_profile(path, icc, metadata.as_ref());
+            cancel.check()?;
+            let (image, size) = match resolution {
+                ImageResolution::THUMBNAIL => (decode_thumbnail(bytes)?, read_jpeg_size(bytes)?),
+                ImageResolution::FULLHD => {
+                    decode_image_for_target(bytes, oriented_size(target, orientation))?
+                }
+                ImageResolution::NATIVE => {
+                    let image = decode_image(bytes)?;
+                    let size = image.dimensions();
+                    (image, size)
+                }
+            };
+            (image, orientation, size, profile)
+        }
+        FileFormat::Tiff => {
+            cancel.check()?;
+            let tiff_page = pages::decode_tiff_page(bytes, page)?;
+            cancel.check()?;
+            let orientation = tiff_page
+                .orientation
+                .map_or(Orientation::Normal, orientation_from_tag);
+            let profile = color.source_profile(path, tiff_page.icc_profile, None);
+            let image = to_rgba_depth(tiff_page.image);
+            let size = image.dimensions();
+            // TIFF pages cannot be decoded scaled, but only what the window shows is kept
+            let image = match resolution {
+                ImageResolution::THUMBNAIL => image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+                ImageResolution::FULLHD => {
+                    let (width, height) = oriented_size(target, orientation);
+                    if size.0 > width || size.1 > height {
+                        image.resize(width, height, FilterType::Triangle)
+                    } else {
+                        image
+                    }
+                }
+                ImageResolution::NATIVE => image,
+            };
+            (image, orientation, size, profile)
+        }
+        _ => return Err(anyhow!("{}: no page {}", path.display(), page + 1)),
+    };
+    cancel.check()?;
+    let frames = frames_to_display(color, still(image), profile.as_deref());
+    Ok((frames, orientation, size))
+}
+
+/// Rasterise an SVG: thumbnails and FULLHD fit into their bounding box, NATIVE
+/// covers `target`, which the loader scales by the viewport zoom.
+/// The native size of a vector image is the size it was rendered at.
+fn get_svg_frames(
+    source: &ImageSource,
+    resolution: &ImageResolution,
+    target: (u32, u32),
+    thumbnails: Option<&ThumbnailCache>,
+    color: &ColorManagement,
+    cancel: &CancelToken,
+) -> Result<(Vec<Frame>, Orientation, (u32, u32))> {
+    let path = source.path;
+    let bytes = source.bytes();
+    let target = match resolution {
+        ImageResolution::THUMBNAIL => {
+            let max_dimension = ThumbnailSize::Large.max_dimension();
+            (max_dimension, max_dimension)
+        }
+        _ => target,
+    };
+    let image = svg::render(path, bytes, target, cancel)?;
+    let size = image.dimensions();
+    if let Some(thumbnails) = thumbnails.filter(|_| *resolution == ImageResolution::THUMBNAIL) {
+        store_thumbnail(thumbnails, color, None, path, image.clone(), Orientation::Normal, size);
+    }
+    Ok((frames_to_display(color, still(image), None), Orientation::Normal, size))
+}
+
+/// Frames decoded by `get_frames`, with what was learnt about the file on the way.
+pub struct Decoded {
+    pub frames: Vec<Frame>,
+    pub orientation: Orientation,
+    /// Native (unscaled) image size
+    pub size: (u32, u32),
+    /// Pages in the file, counted for FULLHD requests
+    pub page_count: Option<usize>,
+    /// Rasterised from vector graphics
+    pub vector: bool,
+}
+
+/// Decode the image of `iref` for the given resolution.
+/// Animations are decoded with all frames at native size, thumbnails show the first frame.
+/// `target` is the window size that FULLHD decodes must cover, `ram_budget`
+/// limits the memory that the frames of an animation may take.
+/// Thumbnails are read from and written to `thumbnails`, if given.
+/// The image is converted to the display colour space.
+pub fn get_frames(
+    iref: &ImageRef,
+    resolution: &ImageResolution,
+    target: (u32, u32),
+    ram_budget: usize,
+    thumbnails: Option<&ThumbnailCache>,
+    color: &ColorManagement,
+    cancel: &CancelToken,
+) -> Result<Decoded> {
+    let path = &iref.path;
+    // the shared thumbnail cache only knows files
+    let thumbnails = thumbnails.filter(|_| iref.entry.is_none());
+    if *resolution == ImageResolution::THUMBNAIL && iref.page == 0 {
+        if let Some((image, orientation, size)) =
+            thumbnails.and_then(|t| load_cached_thumbnail(t, path))
+        {
+            return Ok(Decoded {
+                frames: frames_to_display(color, still(image), None),
+                orientation,
+                size,
+                page_count: None,
+                // SVGs are recognised by their extension alone
+                vector: FileFormat::from_header(path, &[]) == Some(FileFormat::Svg),
+            });
+        }
+    }
+    let source = ImageSource::new(iref)?;
+    cancel.check()?;
+    let format = source.format()?;
+    // counted from the bytes already read, rather than opening the file again
+    let page_count = (*resolution == ImageResolution::FULLHD)
+        .then(|| pages::page_count(path, source.bytes(), format));
+    let (frames, orientation, size) =
+        get_source_frames(&source, resolution, target, ram_budget, thumbnails, color, cancel)?;
+    Ok(Decoded {
+        frames,
+        orientation,
+        size,
+        page_count,
+        vector: format == FileFormat::Svg,
+    })
+}
+
+/// Decode the image in `source`, see `get_frames`.
+/// Returns the frames, the orientation and the native (unscaled) image size.
+fn get_source_frames(
+    source: &ImageSource,
+    resolution: &ImageResolution,
+    target: (u32, u32),
+    ram_budget: usize,
+    thumbnails: Option<&ThumbnailCache>,
+    color: &ColorManagement,
+    cancel: &CancelToken,
+) -> Result<(Vec<Frame>, Orientation, (u32, u32))> {
+    let path = source.path;
+    let format = source.format()?;
+    if source.page > 0 {
+        return get_page_frames(source, source.page, format, resolution, target, color, cancel);
+    }
+    if format == FileFormat::Svg {
+        return get_svg_frames(source, resolution, target, thumbnails, color, cancel);
+    }
+    let metadata = source.metadata(format);
+    let profile = color.source_profile(path, source.icc_profile(format), metadata.as_ref());
+    // the RAW decoder reads files, RAW files in archives are shown through their preview;
+    // a JPEG with a RAW partner is demosaiced from that when zooming in
+    let raw_file = match format {
+        _ if !raw::FULL_DECODE || !source.is_file() => None,
+        FileFormat::Raw => Some(path.to_path_buf()),
+        FileFormat::Jpeg => raw::raw_partner(path),
+        _ => None,
+    };
+    let profile = profile.as_deref();
+    let orientation = metadata
+        .as_ref()
+        .map(|m| m.get_orientation())
+        .unwrap_or(Orientation::Normal);
+    if let (ImageResolution::NATIVE, Some(raw_file)) = (resolution, &raw_file) {
+        cancel.check()?;
+        // the demosaiced image is sRGB, whatever the camera was set to
+        let image = raw::decode(raw_file)?;
+        let size = image.dimensions();
+        return Ok((frames_to_display(color, still(image), None), orientation, size));
+    }
+    match resolution {
+        ImageResolution::THUMBNAIL => {
+            let image = match metadata.as_ref().and_then(load_image_thumbnail_bytes) {
+                Some(thumbnail_bytes) => decode_image(&thumbnail_bytes)?,
+                None => {
+                    let bytes = source.decodable_bytes(format, metadata.as_ref())?;
+                    cancel.check()?;
+                    decode_thumbnail(&bytes)?
+                }
+            };
+            let (image, size) = match metadata.as_ref().and_then(metadata_pixel_size) {
+                Some(size) => (crop_to_aspect(image, size), size),
+                None => {
+                    let size = image.dimensions();
+                    (image, size)
+                }
+            };
+            if let Some(thumbnails) = thumbnails {
+                store_thumbnail(thumbnails, color, profile, path, image.clone(), orientation, size);
+            }
+            Ok((frames_to_display(color, still(image), profile), orientation, size))
+        }
+        ImageResolution::FULLHD => {
+            let target = oriented_size(target, orientation);
+            let bytes = source.decodable_bytes(format, metadata.as_ref())?;
+            cancel.check()?;
+            // zooming in decodes the sensor data, which is larger than the preview
+            let native_size = |size| {
+                if format == FileFormat::Raw && raw_file.is_some() {
+                    metadata.as_ref().and_then(metadata_pixel_size).unwrap_or(size)
+                } else {
+                    size
+                }
+            };
+            // the decoded image is a better thumbnail source than the embedded preview
+            let store = thumbnails.filter(|t| !t.contains(path, ThumbnailSize::Large));
+            let max_dimension = ThumbnailSize::Large.max_dimension();
+            if let Some((pixels, size)) = decode_jpeg_ycbcr(&bytes, Some(target), color, profile)? {
+                let size = native_size(size);
+                if let Some(thumbnails) = store {
+                    // the planes are not RGB, but a DCT-scaled decode is cheap
+                    let thumbnail = decode_thumbnail(&bytes)?;
+                    let thumbnail = thumbnail.thumbnail(max_dimension, max_dimension);
+                    store_thumbnail(thumbnails, color, profile, path, thumbnail, orientation, size);
+                }
+                return Ok((vec![Frame::still(pixels)], orientation, size));
+            }
+            let animation = animation::decode_frames(&bytes, format, ram_budget, cancel)?;
+            let (frames, size) = match animation {
+                Some(frames) => {
+                    let size = frames[0].0.dimensions();
+                    (frames, size)
+                }
+                None => {
+                    let (image, size) = decode_image_for_target(&bytes, target)?;
+                    (still(image), native_size(size))
+                }
+            };
+            if let Some(thumbnails) = store {
+                let thumbnail = frames[0].0.thumbnail(max_dimension, max_dimension);
+                store_thumbnail(thumbnails, color, profile, path, thumbnail, orientation, size);
+            }
+            Ok((frames_to_display(color, frames, profile), orientation, size))
+        }
+        ImageResolution::NATIVE => {
+            let bytes = source.decodable_bytes(format, metadata.as_ref())?;
+            cancel.check()?;
+            if let Some((pixels, size)) = decode_jpeg_ycbcr(&bytes, None, color, profile)? {
+                return Ok((vec![Frame::still(pixels)], orientation, size));
+            }
+            let frames = match animation::decode_frames(&bytes, format, ram_budget, cancel)? {
+                Some(frames) => frames,
+                None => still(decode_image(&bytes)?),
+            };
+            let size = frames[0].0.dimensions();
+            Ok((frames_to_display(color, frames, profile), orientation, size))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct SizedImage {
     pub image_ref: ImageRef,
     pub resolution: ImageResolution,
     pub orientation: Orientation,
-    pub image: DynamicImage,
+    /// Shared with the loader's decode cache
+    pub frames: Arc<Vec<Frame>>,
+    pub full_size: (u32, u32),
+    /// Pages in the file, counted for FULLHD requests
+    pub page_count: Option<usize>,
+    /// Rasterised from vector graphics, which can be rendered again at any size
+    pub vector: bool,
+}
+
+/// One frame of a decoded image, ready for upload.
+#[derive(Debug)]
+pub struct Frame {
+    pub pixels: Pixels,
+    /// How long the frame is shown, zero for still images
+    pub delay: Duration,
+}
+
+impl Frame {
+    fn still(pixels: Pixels) -> Self {
+        Self {
+            pixels,
+            delay: Duration::ZERO,
+        }
+    }
 }
 
 impl SizedImage {
-    pub fn from_request(image_request: ImageRequest) -> Result<Self> {
-        let (image, orientation) = get_rgba_for_path(
-            image_request.reference.path.clone(),
+    pub fn from_request(
+        image_request: ImageRequest,
+        target: (u32, u32),
+        ram_budget: usize,
+        thumbnails: Option<&ThumbnailCache>,
+        color: &ColorManagement,
+        cancel: &CancelToken,
+    ) -> Result<Self, LoadError> {
+        let decoded = get_frames(
+            &image_request.reference,
             &image_request.resolution,
-        )?;
-        Ok(Self {
+            target,
+            ram_budget,
+            thumbnails,
+            color,
+            cancel,
+        )
+        .map_err(|e| LoadError::classify(&e))?;
+        Result::Ok(Self {
             image_ref: image_request.reference,
             resolution: image_request.resolution,
-            orientation,
-            image,
+            orientation: decoded.orientation,
+            frames: Arc::new(decoded.frames),
+            full_size: decoded.size,
+            page_count: decoded.page_count,
+            vector: decoded.vector,
         })
     }
+
+    /// Memory held by the decoded pixels of all frames in bytes.
+    pub fn byte_size(&self) -> usize {
+        self.frames.iter().map(|f| f.pixels.allocated_bytes()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smallest_jpeg_scale_that_covers_the_target() {
+        let fullhd = (1920, 1080);
+        assert_eq!(jpeg_scale_for_target((6000, 4000), fullhd), 4);
+        assert_eq!(jpeg_scale_for_target((7680, 4320), fullhd), 2);
+        // a panorama only needs to cover the width it is fitted to
+        assert_eq!(jpeg_scale_for_target((20000, 2000), fullhd), 1);
+        assert_eq!(jpeg_scale_for_target((800, 600), fullhd), 8);
+    }
 }
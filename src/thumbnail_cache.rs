@@ -0,0 +1,249 @@
+use anyhow::*;
+use image::{DynamicImage, GenericImageView};
+use log::debug;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::UNIX_EPOCH;
+
+/// Numbers the temporary files thumbnails are written to.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Thumbnail sizes of the freedesktop.org thumbnail specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbnailSize {
+    Normal,
+    Large,
+}
+
+impl ThumbnailSize {
+    fn dir_name(&self) -> &'static str {
+        match self {
+            ThumbnailSize::Normal => "normal",
+            ThumbnailSize::Large => "large",
+        }
+    }
+
+    pub fn max_dimension(&self) -> u32 {
+        match self {
+            ThumbnailSize::Normal => 128,
+            ThumbnailSize::Large => 256,
+        }
+    }
+}
+
+type TextChunks = Vec<(String, String)>;
+
+pub struct CachedThumbnail {
+    pub image: DynamicImage,
+    /// Size of the original image, if the thumbnail records it.
+    pub original_size: Option<(u32, u32)>,
+}
+
+/// Thumbnail store shared with file managers, following
+/// https://specifications.freedesktop.org/thumbnail-spec/latest/
+///
+/// Thumbnails are PNG files named by the MD5 of the file URI and carry the
+/// URI and modification time of the original in `Thumb::*` tEXt chunks.
+/// They are stored upright, i.e. with EXIF orientation already applied.
+pub struct ThumbnailCache {
+    root: PathBuf,
+}
+
+impl ThumbnailCache {
+    pub fn new() -> Option<Self> {
+        let mut root = dirs::cache_dir()?;
+        root.push("thumbnails");
+        Some(Self { root })
+    }
+
+    fn uri(path: &Path) -> Result<String> {
+        let path = path.canonicalize()?;
+        let url = url::Url::from_file_path(&path)
+            .map_err(|_| anyhow!("{}: cannot convert to URI", path.display()))?;
+        Ok(url.into())
+    }
+
+    fn mtime(path: &Path) -> Result<u64> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        Ok(modified.duration_since(UNIX_EPOCH)?.as_secs())
+    }
+
+    fn thumbnail_path(&self, uri: &str, size: ThumbnailSize) -> PathBuf {
+        let mut path = self.root.clone();
+        path.push(size.dir_name());
+        path.push(format!("{:x}.png", md5::compute(uri.as_bytes())));
+        path
+    }
+
+    fn text_chunks(path: &Path) -> Result<TextChunks> {
+        let reader = png::Decoder::new(BufReader::new(File::open(path)?)).read_info()?;
+        Ok(reader
+            .info()
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+            .collect())
+    }
+
+    /// Text chunks of the stored thumbnail, if it exists and matches the original.
+    fn valid_chunks(
+        &self,
+        uri: &str,
+        mtime: u64,
+        size: ThumbnailSize,
+    ) -> Result<Option<(PathBuf, TextChunks)>> {
+        let thumbnail_path = self.thumbnail_path(uri, size);
+        if !thumbnail_path.is_file() {
+            return Ok(None);
+        }
+        let chunks = Self::text_chunks(&thumbnail_path)?;
+        let get = |key: &str| {
+            chunks
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        if get("Thumb::URI") != Some(uri) || get("Thumb::MTime") != Some(&mtime.to_string()) {
+            debug!("{}: stale thumbnail for {}", thumbnail_path.display(), uri);
+            return Ok(None);
+        }
+        Ok(Some((thumbnail_path, chunks)))
+    }
+
+    fn load_size(
+        &self,
+        uri: &str,
+        mtime: u64,
+        size: ThumbnailSize,
+    ) -> Result<Option<CachedThumbnail>> {
+        let (thumbnail_path, chunks) = match self.valid_chunks(uri, mtime, size)? {
+            Some(valid) => valid,
+            None => return Ok(None),
+        };
+        let get = |key: &str| {
+            chunks
+                .iter()
+                .find(|(k, _)| k == key)
+                .and_then(|(_, v)| v.parse().ok())
+        };
+        let original_size = get("Thumb::Image::Width").zip(get("Thumb::Image::Height"));
+        let image = image::open(&thumbnail_path)?;
+        Ok(Some(CachedThumbnail {
+            image,
+            original_size,
+        }))
+    }
+
+    /// Look up a valid thumbnail for `path`, preferring the large size.
+    pub fn load(&self, path: &Path) -> Result<Option<CachedThumbnail>> {
+        let uri = Self::uri(path)?;
+        let mtime = Self::mtime(path)?;
+        for size in [ThumbnailSize::Large, ThumbnailSize::Normal] {
+            if let Some(thumbnail) = self.load_size(&uri, mtime, size)? {
+                return Ok(Some(thumbnail));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether a valid thumbnail of the given size exists for `path`.
+    pub fn contains(&self, path: &Path, size: ThumbnailSize) -> bool {
+        let (uri, mtime) = match (Self::uri(path), Self::mtime(path)) {
+            (Result::Ok(uri), Result::Ok(mtime)) => (uri, mtime),
+            _ => return false,
+        };
+        matches!(self.valid_chunks(&uri, mtime, size), Result::Ok(Some(_)))
+    }
+
+    fn create_dir(dir: &Path) -> Result<()> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+        builder.create(dir)?;
+        Ok(())
+    }
+
+    fn write_png(path: &Path, image: &DynamicImage, chunks: &[(&str, String)]) -> Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let (width, height) = image.dimensions();
+        let mut encoder = png::Encoder::new(BufWriter::new(options.open(path)?), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        for (key, value) in chunks {
+            encoder.add_text_chunk(key.to_string(), value.clone())?;
+        }
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(image.to_rgba8().as_raw())?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Store an upright thumbnail for `path`. The image is written to every size
+    /// it has enough pixels for.
+    pub fn store(
+        &self,
+        path: &Path,
+        image: &DynamicImage,
+        original_size: (u32, u32),
+    ) -> Result<()> {
+        let uri = Self::uri(path)?;
+        let mtime = Self::mtime(path)?;
+        let file_size = std::fs::metadata(path)?.len();
+        let (w, h) = image.dimensions();
+        for size in [ThumbnailSize::Large, ThumbnailSize::Normal] {
+            let max_dimension = size.max_dimension();
+            let is_smallest = size == ThumbnailSize::Normal;
+            if w.max(h) < max_dimension && !is_smallest {
+                continue;
+            }
+            let thumbnail = if w.max(h) > max_dimension {
+                image.thumbnail(max_dimension, max_dimension)
+            } else {
+                image.clone()
+            };
+            let thumbnail_path = self.thumbnail_path(&uri, size);
+            let dir = thumbnail_path
+                .parent()
+                .ok_or(anyhow!("invalid thumbnail path"))?;
+            Self::create_dir(dir)?;
+            // write to a temporary file and rename, so readers never see partial files;
+            // its name is unique, as decode threads may store the same thumbnail at once
+            let tmp_path = thumbnail_path.with_extension(format!(
+                "png.vrr-{}-{}.tmp",
+                std::process::id(),
+                TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            Self::write_png(
+                &tmp_path,
+                &thumbnail,
+                &[
+                    ("Thumb::URI", uri.clone()),
+                    ("Thumb::MTime", mtime.to_string()),
+                    ("Thumb::Size", file_size.to_string()),
+                    ("Thumb::Image::Width", original_size.0.to_string()),
+                    ("Thumb::Image::Height", original_size.1.to_string()),
+                    ("Software", "vrr".to_string()),
+                ],
+            )?;
+            std::fs::rename(&tmp_path, &thumbnail_path)?;
+            debug!(
+                "{}: stored thumbnail {}",
+                path.display(),
+                thumbnail_path.display()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+use crate::texture::Plane;
+use log::warn;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Size of each staging buffer; uploads are copied in chunks of rows that fit into one.
+const STAGING_BUFFER_SIZE: usize = 4 << 20;
+
+/// Rows of one texture plane that are still to be copied to the GPU.
+pub struct PlaneUpload {
+    pub frame: usize,
+    pub tile: usize,
+    pub plane: usize,
+    /// `(x, y, width, height)` of the texture within the plane, in plane pixels
+    pub region: (u32, u32, u32, u32),
+    /// Rows copied so far
+    pub rows_done: u32,
+}
+
+impl PlaneUpload {
+    pub fn is_done(&self) -> bool {
+        self.rows_done >= self.region.3
+    }
+}
+
+/// Staging buffers that are written while mapped, copied into textures by the
+/// GPU, and mapped again for reuse once the copy has finished. Their number
+/// bounds the bytes in flight.
+pub struct StagingRing {
+    buffers: Vec<wgpu::Buffer>,
+    /// Buffers that are mapped and can be written
+    ready: Vec<usize>,
+    /// Buffers that were submitted, and are mapped again by the callback;
+    /// an error means the buffer could not be mapped and is replaced
+    mapped_sender: Sender<Result<usize, usize>>,
+    mapped: Receiver<Result<usize, usize>>,
+    max_buffers: usize,
+}
+
+impl StagingRing {
+    /// A ring that holds up to `max_bytes` in buffers, allocated as needed.
+    pub fn new(max_bytes: usize) -> Self {
+        let (mapped_sender, mapped) = channel();
+        Self {
+            buffers: Vec::new(),
+            ready: Vec::new(),
+            mapped_sender,
+            mapped,
+            max_buffers: max_bytes.div_ceil(STAGING_BUFFER_SIZE).max(1),
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Staging Buffer"),
+            size: STAGING_BUFFER_SIZE as u64,
+            usage: wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: true,
+        })
+    }
+
+    /// Take back the buffers whose copies have finished, replacing those
+    /// that failed to map.
+    pub fn recall(&mut self, device: &wgpu::Device) {
+        device.poll(wgpu::Maintain::Poll);
+        while let Ok(mapped) = self.mapped.try_recv() {
+            let index = match mapped {
+                Ok(index) => index,
+                Err(index) => {
+                    warn!("staging buffer {} could not be mapped, replacing it", index);
+                    self.buffers[index] = Self::create_buffer(device);
+                    index
+                }
+            };
+            self.ready.push(index);
+        }
+    }
+
+    fn take(&mut self, device: &wgpu::Device) -> Option<usize> {
+        if let Some(index) = self.ready.pop() {
+            return Some(index);
+        }
+        if self.buffers.len() >= self.max_buffers {
+            return None;
+        }
+        self.buffers.push(Self::create_buffer(device));
+        Some(self.buffers.len() - 1)
+    }
+
+    /// Stage as many rows of `upload` as fit into one buffer and record their
+    /// copy into `texture`. Returns the bytes of pixels staged and the buffer
+    /// used, or `None` while all buffers are in flight.
+    pub fn copy_rows(
+        &mut self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        plane: &Plane,
+        upload: &mut PlaneUpload,
+        texture: &wgpu::Texture,
+    ) -> Option<(usize, usize)> {
+        let (x, y, width, height) = upload.region;
+        let bytes_per_pixel = plane.bytes_per_pixel as usize;
+        let row_len = width as usize * bytes_per_pixel;
+        // copies from buffers need rows aligned to 256 bytes
+        let row_stride = row_len.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize);
+        let rows = ((STAGING_BUFFER_SIZE / row_stride).max(1) as u32).min(height - upload.rows_done);
+        let index = self.take(device)?;
+        let buffer = &self.buffers[index];
+        {
+            let mut staged = buffer
+                .slice(..(rows as usize * row_stride) as u64)
+                .get_mapped_range_mut();
+            for row in 0..rows {
+                let source_row = (y + upload.rows_done + row) as usize;
+                let start = (source_row * plane.width as usize + x as usize) * bytes_per_pixel;
+                staged[row as usize * row_stride..][..row_len]
+                    .copy_from_slice(&plane.bytes[start..start + row_len]);
+            }
+        }
+        buffer.unmap();
+        encoder.copy_buffer_to_texture(
+            wgpu::ImageCopyBuffer {
+                buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(row_stride as u32),
+                    rows_per_image: Some(rows),
+                },
+            },
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: 0,
+                    y: upload.rows_done,
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::Extent3d {
+                width,
+                height: rows,
+                depth_or_array_layers: 1,
+            },
+        );
+        upload.rows_done += rows;
+        Some((rows as usize * row_len, index))
+    }
+
+    /// Map the `used` buffers again, after the copies from them were submitted.
+    pub fn remap(&self, used: Vec<usize>) {
+        for index in used {
+            let sender = self.mapped_sender.clone();
+            self.buffers[index]
+                .slice(..)
+                .map_async(wgpu::MapMode::Write, move |result| {
+                    sender.send(result.map(|_| index).map_err(|_| index)).ok();
+                });
+        }
+    }
+}
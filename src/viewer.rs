@@ -1,83 +1,76 @@
-use crate::image_loader::ImageLoader;
+use crate::animation::Playback;
+use crate::image_loader::{ImageLoader, ImageRef};
 use crate::input_events::{on_event, Inputs};
-use crate::layers::{Layer, Layers};
-use crate::texture::SizedImage;
-use crate::viewport::{Uniforms, Viewport};
+use crate::layers::{Layer, Layers, Vertex, INDICES};
+use crate::load_error::LoadError;
+use crate::mipmap::MipmapGenerator;
+use crate::texture::{FilterMode, ImageResolution, SizedImage, ToneMapping};
+use crate::viewport::{RenderSettings, Uniforms, Viewport};
 use anyhow::anyhow;
 use anyhow::*;
 
 use crate::config::Config;
 
 use crate::overlay::{HorizontalPosition, Overlay, Position, VerticalPosition};
+use crate::scan;
+use crate::sort::SortOrder;
 use crate::storage::{Storage, TAG_STARRED};
+use crate::wake;
 use log::debug;
 use logging_timer::{executing, timer};
 use std::iter;
 use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use wgpu::util::DeviceExt;
 use winit::event_loop::EventLoop;
 use winit::window::{Window, WindowBuilder};
 use crate::fps_meter::FpsMeter;
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-struct Vertex {
-    position: [f32; 3],
-    tex_coords: [f32; 2],
+/// How long a status message stays in the bottom-left corner
+const STATUS_DURATION: Duration = Duration::from_secs(2);
+
+/// Vector images are rendered again once shown at this many times the pixels
+/// they were rasterised with, so that each zoom step does not start a render.
+const VECTOR_RERENDER_RATIO: f64 = 1.5;
+
+/// Subdirectories listed while there are no images, one per number key
+const MAX_SUBDIRECTORIES: usize = 9;
+
+/// Background behind the reason an image failed to load
+const ERROR_BACKGROUND: wgpu::Color = wgpu::Color {
+    r: 0.12,
+    g: 0.05,
+    b: 0.05,
+    a: 1.0,
+};
+
+/// File name for display; names that are not valid UTF-8 are shown lossily.
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
 }
 
-impl Vertex {
-    fn desc() -> wgpu::VertexBufferLayout<'static> {
-        use std::mem;
-        wgpu::VertexBufferLayout {
-            array_stride: mem::size_of::<Vertex>() as wgpu::BufferAddress,
-            step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    offset: 0,
-                    shader_location: 0,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
-                    format: wgpu::VertexFormat::Float32x2,
-                },
-            ],
-        }
-    }
+/// The directory of `paths` if it is a single directory, or an image or archive in one.
+fn browsed_directory(paths: &[PathBuf]) -> Option<PathBuf> {
+    let path = match paths {
+        [path] => path,
+        _ => return None,
+    };
+    let dir = if path.is_dir() { path.as_path() } else { path.parent()? };
+    // a file name without directory has the empty path as parent
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    dir.canonicalize().ok()
 }
 
-// two triangles that result in a square
-const VERTICES: &[Vertex] = &[
-    Vertex {
-        position: [-1., 1., 0.0],
-        tex_coords: [0.0, 0.0],
-    }, // A
-    Vertex {
-        position: [-1., -1., 0.0],
-        tex_coords: [0.0, 1.0],
-    }, // B
-    Vertex {
-        position: [1., -1., 0.0],
-        tex_coords: [1.0, 1.0],
-    }, // C
-    Vertex {
-        position: [1., 1., 0.0],
-        tex_coords: [1.0, 0.0],
-    },
-];
-
-// 6 indices, forming two triangles
-const INDICES: &[u16] = &[0, 1, 2, 0, 2, 3];
-
 pub struct Viewer {
     surface: wgpu::Surface,
     device: wgpu::Device,
     queue: wgpu::Queue,
     surface_config: wgpu::SurfaceConfiguration,
     render_pipeline: wgpu::RenderPipeline,
-    vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
     fps_meter: FpsMeter,
     pub config: Config,
@@ -88,13 +81,40 @@ pub struct Viewer {
     pub view: Viewport,
     pub inputs: Inputs,
     pub overlay: Overlay,
+    status: Option<(String, Instant)>,
+    pub rendering: RenderSettings,
+    pub playback: Playback,
+    /// Something changed since the last frame was drawn
+    redraw: bool,
+    /// Whether the last frame showed the error placeholder
+    error_shown: bool,
+    /// Directories and archives that can be opened with the number keys while there are no images
+    subdirectories: Vec<PathBuf>,
+    /// Why the opened path shows no images, if it could not be read
+    open_error: Option<String>,
 }
 
 impl Viewer {
     pub async fn new(window: &Window, config: Config) -> Result<Self> {
         let tmr = timer!("Renderer::new");
-        let loader = ImageLoader::from_path(config.path.clone(), config.preload)?;
         let size = window.inner_size();
+        let target_size = (size.width, size.height);
+        // without images the window shows how to open some, and the directory
+        // stays watched so that images added to it show up
+        let (loader, open_error) = match ImageLoader::from_config(&config, target_size) {
+            Result::Ok(loader) => (loader, None),
+            Err(e) => {
+                log::error!("{}", e);
+                let loader = browsed_directory(&config.paths)
+                    .and_then(|dir| {
+                        ImageLoader::from_path(dir, &config, target_size)
+                            .map_err(|e| log::warn!("{}", e))
+                            .ok()
+                    })
+                    .unwrap_or_else(|| ImageLoader::from_paths(Vec::new(), &config, target_size));
+                (loader, Some(e.to_string()))
+            }
+        };
 
         executing!(tmr, "Instance::new");
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
@@ -125,7 +145,11 @@ impl Viewer {
                     features: wgpu::Features::empty(),
                     // WebGL doesn't support all of wgpu's features, so if
                     // we're building for the web we'll have to disable some.
-                    limits: wgpu::Limits::default(),
+                    // Larger textures mean fewer tiles for large images.
+                    limits: wgpu::Limits {
+                        max_texture_dimension_2d: adapter.limits().max_texture_dimension_2d,
+                        ..wgpu::Limits::default()
+                    },
                 },
                 None, // Trace path
             )
@@ -174,7 +198,28 @@ impl Viewer {
                     wgpu::BindGroupLayoutEntry {
                         binding: 1,
                         visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    // chroma planes of Y'CbCr images
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
                         count: None,
                     },
                 ],
@@ -266,11 +311,6 @@ impl Viewer {
             multiview: None,
         });
 
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Vertex Buffer"),
-            contents: bytemuck::cast_slice(VERTICES),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
         let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Index Buffer"),
             contents: bytemuck::cast_slice(INDICES),
@@ -288,27 +328,53 @@ impl Viewer {
             return Err(anyhow!("wgpu error"));
         }
 
+        let mipmaps = MipmapGenerator::new(
+            &device,
+            &[
+                wgpu::TextureFormat::Rgba8UnormSrgb,
+                wgpu::TextureFormat::Rgba16Float,
+                wgpu::TextureFormat::R8Unorm,
+            ],
+        )?;
         let overlay = Overlay::new(&device, &queue, surface_config.format);
         let storage = Storage::new()?;
 
-        Ok(Self {
+        let mut viewer = Self {
             surface,
             device,
             queue,
             surface_config,
             size,
             render_pipeline,
-            vertex_buffer,
             index_buffer,
             loader,
             fps_meter: FpsMeter::new(),
             inputs: Inputs::new(),
-            layers: Layers::new(texture_bind_group_layout, uniform_bind_group_layout),
+            layers: Layers::new(
+                texture_bind_group_layout,
+                uniform_bind_group_layout,
+                mipmaps,
+                config.upload_budget,
+                config.gpu_budget,
+            ),
+            rendering: RenderSettings {
+                filter: config.filter,
+                exposure: config.exposure,
+                tone_mapping: config.tonemap,
+            },
             view: Viewport::new(),
             storage,
             config,
             overlay,
-        })
+            status: None,
+            playback: Playback::new(),
+            redraw: true,
+            error_shown: false,
+            subdirectories: Vec::new(),
+            open_error,
+        };
+        viewer.update_subdirectories();
+        Ok(viewer)
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -317,6 +383,8 @@ impl Viewer {
             self.surface_config.width = new_size.width;
             self.surface_config.height = new_size.height;
             self.surface.configure(&self.device, &self.surface_config);
+            self.loader
+                .set_target_size((new_size.width, new_size.height));
         }
     }
 
@@ -340,7 +408,7 @@ impl Viewer {
         layer: &'a Layer,
         alpha: f64,
     ) {
-        let image_size = (layer.texture.width() as f64, layer.texture.height() as f64);
+        let image_size = (layer.size.0 as f64, layer.size.1 as f64);
         let screen_size = (self.size.width as f64, self.size.height as f64);
         self.queue.write_buffer(
             &layer.uniform_buffer,
@@ -350,27 +418,33 @@ impl Viewer {
                 screen_size,
                 layer.orientation,
                 alpha,
+                layer.ycbcr,
+                &self.rendering,
             )),
         );
-        render_pass.set_bind_group(0, &layer.texture_bind_group, &[]);
         render_pass.set_bind_group(1, &layer.uniform_bind_group, &[]);
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
         render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-        render_pass.draw_indexed(0..INDICES.len() as u32, 0, 0..1);
+        // one quad per tile, positioned within the image by its vertices
+        for tile in layer.tiles(self.playback.frame()) {
+            render_pass.set_bind_group(0, &tile.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, tile.vertex_buffer.slice(..));
+            render_pass.draw_indexed(0..INDICES.len() as u32, 0, 0..1);
+        }
     }
 
     pub fn update_overlay(&mut self) {
-        // draw filename top-left
-        let filename = format!(
-            "{}",
-            self.loader
-                .current()
-                .path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-        );
+        let current = self.loader.current();
+
+        // draw filename top-left, with the page of multi-page files
+        let mut filename = current
+            .as_ref()
+            .map(|iref| display_name(&iref.path))
+            .unwrap_or_default();
+        if let (page, Some(count)) = self.loader.page() {
+            if count > 1 {
+                filename += &format!("  page {}/{}", page + 1, count);
+            }
+        }
         self.overlay.update(
             Position::new(HorizontalPosition::Left, VerticalPosition::Top),
             &self.device,
@@ -381,10 +455,9 @@ impl Viewer {
 
         // draw starred marker top-right
 
-        let starred = if self.storage.entry(&self.loader.current()).has_tag(TAG_STARRED) {
-            "★"
-        } else {
-            ""
+        let starred = match &current {
+            Some(iref) if self.storage.entry(iref).has_tag(TAG_STARRED) => "★",
+            _ => "",
         };
         self.overlay.update(
             Position::new(HorizontalPosition::Right, VerticalPosition::Top),
@@ -394,6 +467,32 @@ impl Viewer {
             starred.to_owned()
         );
 
+        // draw the reason centred in place of an image that failed to load,
+        // or how to open images while there are none
+        let error = self
+            .failed_image()
+            .map(|(iref, e)| format!("{}\n{}", display_name(&iref.path), e))
+            .or_else(|| (self.loader.len() == 0).then(|| self.empty_message()));
+        self.overlay.update(
+            Position::new(HorizontalPosition::Center, VerticalPosition::Center),
+            &self.device,
+            &self.queue,
+            &self.size,
+            error.unwrap_or_default()
+        );
+
+        let status = match &self.status {
+            Some((text, since)) if since.elapsed() < STATUS_DURATION => text.clone(),
+            _ => String::new(),
+        };
+        self.overlay.update(
+            Position::new(HorizontalPosition::Left, VerticalPosition::Bottom),
+            &self.device,
+            &self.queue,
+            &self.size,
+            status
+        );
+
         let fps = format!("{} fps", self.fps_meter.fps());
         self.overlay.update(
             Position::new(HorizontalPosition::Right, VerticalPosition::Bottom),
@@ -420,6 +519,13 @@ impl Viewer {
                 label: Some("Render Encoder"),
             });
 
+        self.error_shown = self.failed_image().is_some();
+        let background = if self.error_shown {
+            ERROR_BACKGROUND
+        } else {
+            wgpu::Color::BLACK
+        };
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
@@ -427,12 +533,7 @@ impl Viewer {
                     view: &view,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.0,
-                            g: 0.0,
-                            b: 0.0,
-                            a: 1.0,
-                        }),
+                        load: wgpu::LoadOp::Clear(background),
                         store: true,
                     },
                 })],
@@ -441,12 +542,18 @@ impl Viewer {
 
             render_pass.set_pipeline(&self.render_pipeline);
 
-            let iref = self.loader.current();
-            if let Some(layer) = self.layers.get_layer(&iref) {
+            if let Some(layer) = self.current_layer() {
                 self.draw_layer(&mut render_pass, layer, 1.0);
             }
         }
 
+        if self.needs_native_resolution() {
+            self.loader.request_native();
+        }
+        if self.needs_vector_render() {
+            self.loader.request_vector_render(self.view.zoom);
+        }
+
         self.queue.submit(iter::once(encoder.finish()));
         self.update_overlay();
         self.overlay.render(&self.device, &self.queue, &output);
@@ -454,14 +561,276 @@ impl Viewer {
         Ok(())
     }
 
+    /// Whether the current vector image is shown noticeably larger than it was
+    /// rasterised, so that rendering it again keeps it crisp.
+    fn needs_vector_render(&self) -> bool {
+        let layer = match self.current_layer() {
+            Some(layer) => layer,
+            None => return false,
+        };
+        // the thumbnail is shown until the first render arrives
+        if !layer.vector || layer.resolution == ImageResolution::THUMBNAIL {
+            return false;
+        }
+        let texture_size = (layer.size.0 as f64, layer.size.1 as f64);
+        let (display_width, display_height) = self.view.display_size(
+            texture_size,
+            (self.size.width as f64, self.size.height as f64),
+            layer.orientation,
+        );
+        display_width * display_height > texture_size.0 * texture_size.1 * VECTOR_RERENDER_RATIO
+    }
+
+    /// Whether the current image is shown larger than its downscaled texture,
+    /// so that decoding it at native resolution would add detail.
+    fn needs_native_resolution(&self) -> bool {
+        let layer = match self.current_layer() {
+            Some(layer) => layer,
+            None => return false,
+        };
+        if layer.resolution != ImageResolution::FULLHD || !layer.is_downscaled() {
+            return false;
+        }
+        let texture_size = (layer.size.0 as f64, layer.size.1 as f64);
+        let (display_width, display_height) = self.view.display_size(
+            texture_size,
+            (self.size.width as f64, self.size.height as f64),
+            layer.orientation,
+        );
+        // aspect ratio is preserved, so comparing areas is orientation independent
+        display_width * display_height > texture_size.0 * texture_size.1
+    }
+
+    /// What is shown while there are no images: where none were found, and how to open some.
+    fn empty_message(&self) -> String {
+        let mut lines = vec![match (&self.open_error, self.directory()) {
+            (Some(e), _) => e.clone(),
+            (None, Some(dir)) => format!("no images in {}", dir.to_string_lossy()),
+            (None, None) => "no images".to_string(),
+        }];
+        lines.push("drop an image, directory or archive here to open it".to_string());
+        for (i, path) in self.subdirectories.iter().take(MAX_SUBDIRECTORIES).enumerate() {
+            lines.push(format!("{}  {}", i + 1, display_name(path)));
+        }
+        if self.directory().is_some_and(|dir| dir.parent().is_some()) {
+            lines.push("backspace  parent directory".to_string());
+        }
+        lines.join("\n")
+    }
+
+    /// The directory being browsed, if a single directory, or an image or
+    /// archive in it, was opened.
+    fn directory(&self) -> Option<PathBuf> {
+        browsed_directory(&self.config.paths)
+    }
+
+    fn update_subdirectories(&mut self) {
+        self.subdirectories = match self.directory() {
+            Some(dir) if self.loader.len() == 0 => scan::list_subdirectories(&dir)
+                .map_err(|e| log::warn!("{}", e))
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        };
+    }
+
+    /// Show `path` in place of the images shown so far: a directory, an image
+    /// within its directory, or an archive. The current images stay if it
+    /// cannot be opened.
+    pub fn open(&mut self, path: PathBuf) {
+        let mut config = self.config.clone();
+        config.paths = vec![path];
+        config.browse_parent = true;
+        let loader = match ImageLoader::from_config(&config, (self.size.width, self.size.height)) {
+            Result::Ok(loader) => loader,
+            Err(e) => {
+                log::error!("{}", e);
+                self.show_status(e.to_string());
+                return;
+            }
+        };
+        debug!("opened {:?} with {} images", config.paths[0], loader.len());
+        self.loader = loader;
+        self.config = config;
+        self.open_error = None;
+        self.layers.retain(&[]);
+        self.playback = Playback::new();
+        self.view.zoom = 1.0;
+        self.view.pan = (0.0, 0.0);
+        self.update_subdirectories();
+        self.redraw = true;
+    }
+
+    /// Open the directory above the one being browsed.
+    pub fn open_parent(&mut self) {
+        match self.directory().as_deref().and_then(Path::parent) {
+            Some(parent) => self.open(parent.to_path_buf()),
+            None => self.show_status("no parent directory".to_string()),
+        }
+    }
+
+    /// Open one of the listed subdirectories while there are no images, counting from 0.
+    pub fn open_subdirectory(&mut self, index: usize) {
+        if self.loader.len() > 0 || index >= MAX_SUBDIRECTORIES {
+            return;
+        }
+        if let Some(path) = self.subdirectories.get(index).cloned() {
+            self.open(path);
+        }
+    }
+
+    /// Show a short message in the bottom-left corner.
+    pub fn show_status(&mut self, text: String) {
+        self.status = Some((text, Instant::now()));
+    }
+
+    /// Re-sort the images, keeping the current one selected.
+    pub fn set_sort_order(&mut self, order: SortOrder, reverse: bool) {
+        self.loader.set_sort_order(order, reverse);
+        let direction = if reverse { " (reversed)" } else { "" };
+        self.show_status(format!("sort: {}{}", order.name(), direction));
+    }
+
+    pub fn set_filter(&mut self, filter: FilterMode) {
+        self.rendering.filter = filter;
+        self.show_status(format!("filter: {}", filter.name()));
+    }
+
+    /// Exposure in stops, for viewing HDR images.
+    pub fn set_exposure(&mut self, exposure: f32) {
+        self.rendering.exposure = exposure;
+        self.show_status(format!("exposure: {:+.1} EV", exposure));
+    }
+
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.rendering.tone_mapping = tone_mapping;
+        self.show_status(format!("tone mapping: {}", tone_mapping.name()));
+    }
+
+    /// Pick up added, removed and changed files in watched directories.
+    pub fn poll_file_changes(&mut self) {
+        let len = self.loader.len();
+        let invalidated = self.loader.poll_changes();
+        if !invalidated.is_empty() || self.loader.len() != len {
+            self.redraw = true;
+        }
+        // the first image to appear replaces the list of directories, the last to go brings it back
+        if (len == 0) != (self.loader.len() == 0) {
+            self.update_subdirectories();
+        }
+        for iref in invalidated {
+            self.layers.remove(&iref);
+        }
+    }
+
+    /// Draw again at the next opportunity, e.g. after input.
+    pub fn request_redraw(&mut self) {
+        self.redraw = true;
+    }
+
+    /// Whether a redraw was requested since the last call.
+    pub fn take_redraw(&mut self) -> bool {
+        std::mem::take(&mut self.redraw)
+    }
+
+    /// Upload decoded images, advance the current animation and expire the status
+    /// message. Returns when this next needs to happen, if at all; decoded images
+    /// and file changes wake the event loop themselves.
+    pub fn update(&mut self) -> Option<Instant> {
+        let now = Instant::now();
+        // a slice of the queued uploads per frame, nearest images first
+        let loader = &self.loader;
+        if self.layers.upload(&self.device, &self.queue, |req| loader.priority(req)) {
+            self.redraw = true;
+        }
+        if let Some(iref) = self.loader.current() {
+            let delays = self
+                .layers
+                .get_layer(&iref)
+                .map(|layer| layer.delays())
+                .unwrap_or_default();
+            if self.playback.update(&iref, &delays, now) {
+                self.redraw = true;
+            }
+        }
+        // failures are not added as layers, so they need a redraw of their own
+        if self.failed_image().is_some() != self.error_shown {
+            self.redraw = true;
+        }
+        if let Some((_, since)) = &self.status {
+            if since.elapsed() >= STATUS_DURATION {
+                self.status = None;
+                self.redraw = true;
+            }
+        }
+        let status_expiry = self.status.as_ref().map(|(_, since)| *since + STATUS_DURATION);
+        // uploads continue with the next frame
+        let upload = self.layers.is_uploading().then_some(now);
+        [self.playback.deadline(), status_expiry, upload, self.loader.deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    fn current_layer(&self) -> Option<&Layer> {
+        self.layers.get_layer(&self.loader.current()?)
+    }
+
+    /// The current image and why it failed to load, while there is nothing else to show for it.
+    fn failed_image(&self) -> Option<(ImageRef, &LoadError)> {
+        let iref = self.loader.current()?;
+        if self.layers.get_layer(&iref).is_some() {
+            return None;
+        }
+        let error = self.loader.error(&iref)?;
+        Some((iref, error))
+    }
+
+    fn frame_count(&self) -> usize {
+        self.current_layer().map_or(0, |layer| layer.frames.len())
+    }
+
+    pub fn toggle_playback(&mut self) {
+        self.playback.toggle_pause();
+        let state = if self.playback.is_paused() { "paused" } else { "playing" };
+        self.show_status(state.to_string());
+    }
+
+    /// Show the next or previous animation frame, pausing playback.
+    pub fn step_frame(&mut self, forward: bool) {
+        let frame_count = self.frame_count();
+        if frame_count < 2 {
+            self.show_status("not animated".to_string());
+            return;
+        }
+        self.playback.step(forward, frame_count);
+        self.show_status(format!("frame {}/{}", self.playback.frame() + 1, frame_count));
+    }
+
+    /// Show the next or previous page of a multi-page file.
+    pub fn step_page(&mut self, forward: bool) -> Result<()> {
+        if !self.loader.step_page(forward)? {
+            let status = match self.loader.page() {
+                (_, Some(count)) if count > 1 => {
+                    if forward { "last page" } else { "first page" }
+                }
+                _ => "single page",
+            };
+            self.show_status(status.to_string());
+        }
+        Ok(())
+    }
+
+    pub fn change_playback_speed(&mut self, faster: bool) {
+        let speed = self.playback.change_speed(faster);
+        self.show_status(format!("speed: {}x", speed));
+    }
+
     pub fn add_image(&mut self, si: SizedImage) -> Result<()> {
         debug!("set image: {:?} {:?}", si.image_ref.path, si.resolution);
-        self.layers
-            .add_layer_from_sized_image(&self.device, &self.queue, si)?;
+        // shown once `update` has uploaded it
+        self.layers.add_layer_from_sized_image(&self.device, si);
 
-        self.loader.preload(self.loader.preload)
-            .map_err(|e| anyhow!("error preloading images: {}", e))
-            .ok();
+        self.loader.preload();
         self.loader.clear_cache();
         self.layers.retain(&self.loader.cached());
 
@@ -471,6 +840,7 @@ impl Viewer {
 
 pub async fn run(config: Config) {
     let event_loop = EventLoop::new();
+    wake::init(event_loop.create_proxy());
     let window = WindowBuilder::new()
         .with_transparent(true)
         .build(&event_loop)
@@ -481,6 +851,8 @@ pub async fn run(config: Config) {
         .unwrap();
 
     event_loop.run(move |event, _, control_flow| {
+        viewer.poll_file_changes();
+        viewer.loader.update_skimming();
         for image in viewer.loader.images() {
             viewer
                 .add_image(image)
@@ -489,13 +861,15 @@ pub async fn run(config: Config) {
         }
 
         // set window title to filename
-        {
-            let iref = viewer.loader.current();
+        if let Some(iref) = viewer.loader.current() {
+            let parent = iref.path.parent().unwrap_or(&iref.path);
             window.set_title(&format!(
                 "{} - {}",
-                iref.path.file_name().unwrap().to_str().unwrap(),
-                iref.path.parent().unwrap().to_str().unwrap()
+                display_name(&iref.path),
+                parent.to_string_lossy()
             ));
+        } else {
+            window.set_title("vrr");
         }
 
         pollster::block_on(on_event(&window, event, control_flow, &mut viewer));
@@ -1,4 +1,5 @@
 use crate::layers::Orientation;
+use crate::texture::{FilterMode, ToneMapping};
 
 fn m44(a: f64, b: f64, p: f64, q: f64) -> nalgebra::Matrix4<f64> {
     nalgebra::matrix![
@@ -23,6 +24,15 @@ fn proj_xy(m: nalgebra::Matrix4<f64>, (x, y): (f64, f64)) -> (f64, f64) {
     (v.x, v.y)
 }
 
+/// How images are drawn, adjustable while viewing.
+#[derive(Debug, Clone, Copy)]
+pub struct RenderSettings {
+    pub filter: FilterMode,
+    /// In stops, applied to linear light before tone mapping
+    pub exposure: f32,
+    pub tone_mapping: ToneMapping,
+}
+
 pub struct Viewport {
     pub cursor: (f64, f64),
     pub zoom: f64,
@@ -97,14 +107,8 @@ impl Viewport {
         )
     }
 
-    pub fn to_uniforms(
-        &self,
-        image_size: (f64, f64),
-        screen_size: (f64, f64),
-        orientation: Orientation,
-        alpha: f64,
-    ) -> Uniforms {
-        let m_orientation = match orientation {
+    fn orientation_matrix(orientation: Orientation) -> nalgebra::Matrix4<f64> {
+        match orientation {
             Orientation::Normal | Orientation::Unspecified => m_orient(1.0, 0.0, 0.0, 1.0),
             Orientation::Rotate90 => m_orient(0.0, 1.0, -1.0, 0.0),
             Orientation::Rotate90HorizontalFlip => m_orient(0.0, -1.0, -1.0, 0.0),
@@ -113,7 +117,34 @@ impl Viewport {
             Orientation::VerticalFlip => m_orient(1.0, 0.0, 0.0, -1.0),
             Orientation::Rotate180 => m_orient(-1.0, 0.0, 0.0, -1.0),
             Orientation::Rotate270 => m_orient(0.0, -1.0, 1.0, 0.0),
-        };
+        }
+    }
+
+    /// Size in screen pixels that an image of `image_size` currently occupies.
+    pub fn display_size(
+        &self,
+        image_size: (f64, f64),
+        screen_size: (f64, f64),
+        orientation: Orientation,
+    ) -> (f64, f64) {
+        let oriented = proj_xy(Self::orientation_matrix(orientation), image_size);
+        let scale = Self::scale(oriented, screen_size);
+        (
+            scale.0 * self.zoom * screen_size.0,
+            scale.1 * self.zoom * screen_size.1,
+        )
+    }
+
+    pub fn to_uniforms(
+        &self,
+        image_size: (f64, f64),
+        screen_size: (f64, f64),
+        orientation: Orientation,
+        alpha: f64,
+        ycbcr: bool,
+        settings: &RenderSettings,
+    ) -> Uniforms {
+        let m_orientation = Self::orientation_matrix(orientation);
         let scale = Self::scale(proj_xy(m_orientation, image_size), screen_size);
         let projection = self.projection(scale) * m_orientation;
         let cursor = proj_xy(self.mscale(scale) * self.mscreen(screen_size), self.cursor);
@@ -122,7 +153,11 @@ impl Viewport {
             image_size: [image_size.0 as f32, image_size.1 as f32],
             cursor: [cursor.0 as f32, cursor.1 as f32],
             alpha: alpha as f32,
-            padding: [0; 3],
+            filter_mode: settings.filter as u32,
+            exposure: settings.exposure,
+            tone_mapping: settings.tone_mapping as u32,
+            ycbcr: ycbcr as u32,
+            _padding: [0; 3],
         }
     }
 }
@@ -134,7 +169,12 @@ pub struct Uniforms {
     image_size: [f32; 2],
     cursor: [f32; 2],
     alpha: f32,
-    padding: [u32; 3],
+    filter_mode: u32,
+    exposure: f32,
+    tone_mapping: u32,
+    ycbcr: u32,
+    // WGSL rounds the struct size up to a multiple of 16 bytes
+    _padding: [u32; 3],
 }
 
 impl Uniforms {
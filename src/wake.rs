@@ -0,0 +1,18 @@
+use std::sync::{Mutex, OnceLock};
+use winit::event_loop::EventLoopProxy;
+
+/// Wakes the event loop from other threads, e.g. once an image is decoded, so
+/// that it can sleep until then instead of polling.
+static PROXY: OnceLock<Mutex<EventLoopProxy<()>>> = OnceLock::new();
+
+/// Send wake-ups to the event loop of `proxy`.
+pub fn init(proxy: EventLoopProxy<()>) {
+    PROXY.set(Mutex::new(proxy)).ok();
+}
+
+/// Wake the event loop; nothing happens before `init` or after it has exited.
+pub fn wake() {
+    if let Some(proxy) = PROXY.get() {
+        proxy.lock().unwrap().send_event(()).ok();
+    }
+}
@@ -0,0 +1,124 @@
+use crate::wake;
+use anyhow::*;
+use log::{debug, warn};
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RemoveKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+/// A move out of a watched directory is only reported once no matching move
+/// into a watched directory arrived within this time.
+const RENAME_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A file or directory appeared.
+    Added(PathBuf),
+    /// A file or directory disappeared.
+    Removed(PathBuf),
+    /// A file was written and closed.
+    Modified(PathBuf),
+    Renamed(PathBuf, PathBuf),
+}
+
+/// Watches directories through inotify and reports changes as `Change`s.
+pub struct DirectoryWatcher {
+    watcher: RecommendedWatcher,
+    receiver: Receiver<notify::Result<Event>>,
+    pending_renames: HashMap<usize, (PathBuf, Instant)>,
+}
+
+impl DirectoryWatcher {
+    pub fn new() -> Result<Self> {
+        let (sender, receiver) = channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            sender.send(event).ok();
+            wake::wake();
+        })?;
+        Ok(Self {
+            watcher,
+            receiver,
+            pending_renames: HashMap::new(),
+        })
+    }
+
+    pub fn watch(&mut self, dir: &Path, recursive: bool) -> Result<()> {
+        let mode = if recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        self.watcher
+            .watch(dir, mode)
+            .map_err(|e| anyhow!("{}: cannot watch: {}", dir.display(), e))
+    }
+
+    fn translate(&mut self, event: Event, changes: &mut Vec<Change>) {
+        let mut paths = event.paths.into_iter();
+        let path = match paths.next() {
+            Some(path) => path,
+            None => return,
+        };
+        match event.kind {
+            EventKind::Create(CreateKind::File | CreateKind::Folder | CreateKind::Any) => {
+                changes.push(Change::Added(path))
+            }
+            EventKind::Remove(RemoveKind::File | RemoveKind::Folder | RemoveKind::Any) => {
+                changes.push(Change::Removed(path))
+            }
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                changes.push(Change::Modified(path))
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => match event.attrs.tracker() {
+                Some(cookie) => {
+                    self.pending_renames.insert(cookie, (path, Instant::now()));
+                }
+                None => changes.push(Change::Removed(path)),
+            },
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                let from = event
+                    .attrs
+                    .tracker()
+                    .and_then(|cookie| self.pending_renames.remove(&cookie));
+                match from {
+                    Some((from, _)) => changes.push(Change::Renamed(from, path)),
+                    None => changes.push(Change::Added(path)),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// When the oldest unmatched move out of a watched directory times out.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending_renames
+            .values()
+            .map(|(_, time)| *time + RENAME_TIMEOUT)
+            .min()
+    }
+
+    /// Collect the changes since the last call.
+    pub fn poll(&mut self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        while let Result::Ok(event) = self.receiver.try_recv() {
+            match event {
+                Result::Ok(event) => {
+                    debug!("watch event: {:?}", event);
+                    self.translate(event, &mut changes);
+                }
+                Err(e) => warn!("watch error: {}", e),
+            }
+        }
+        let now = Instant::now();
+        self.pending_renames.retain(|_, (path, time)| {
+            if now.duration_since(*time) < RENAME_TIMEOUT {
+                return true;
+            }
+            changes.push(Change::Removed(path.clone()));
+            false
+        });
+        changes
+    }
+}